@@ -0,0 +1,118 @@
+//! Galois/Counter Mode as described in NIST SP 800-38D, restricted to 96 bit nonces and 128 bit tags.
+use crate::{BlockCipher, CtrMode, BLOCK_SIZE, CTR_NONCE_SIZE};
+use core::fmt;
+
+/// size of the authentication tag produced by Gcm
+pub const TAG_SIZE: usize = 16;
+
+/// returned when the authentication tag of a message doesn't match, the data is left encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMismatch;
+
+impl fmt::Display for TagMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gcm authentication tag mismatch")
+    }
+}
+
+impl std::error::Error for TagMismatch {}
+
+/// authenticated encryption with associated data, built on any of the aes block cipher types
+#[derive(Clone)]
+pub struct Gcm<C> {
+    cipher: C,
+    // hash subkey, the encryption of the all zero block
+    h: u128,
+}
+
+/// AES-128 in Galois/Counter Mode
+pub type Aes128Gcm = Gcm<crate::Aes128>;
+/// AES-192 in Galois/Counter Mode
+pub type Aes192Gcm = Gcm<crate::Aes192>;
+/// AES-256 in Galois/Counter Mode
+pub type Aes256Gcm = Gcm<crate::Aes256>;
+
+impl<C: BlockCipher + CtrMode> Gcm<C> {
+    /// wrap a keyed block cipher
+    pub fn new(cipher: C) -> Self {
+        let mut h = [0u8; BLOCK_SIZE];
+        cipher.encrypt_block(&mut h);
+        let h = u128::from_be_bytes(h);
+        Self { cipher, h }
+    }
+
+    /// encrypt data in place, returning the tag that authenticates both data and aad
+    pub fn encrypt(&self, nonce: &[u8], aad: &[u8], data: &mut [u8]) -> [u8; TAG_SIZE] {
+        assert_eq!(CTR_NONCE_SIZE, nonce.len());
+        // counter 1 is reserved for the tag, the data keystream begins at 2
+        self.cipher.apply_keystream(nonce, 2, data);
+        self.tag(nonce, aad, data)
+    }
+
+    /// check the tag then decrypt data in place
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), TagMismatch> {
+        assert_eq!(CTR_NONCE_SIZE, nonce.len());
+        let expected = self.tag(nonce, aad, data);
+        if tag.len() != TAG_SIZE {
+            return Err(TagMismatch);
+        }
+        // compare every byte so the time taken doesn't reveal where the tags differ
+        let diff = expected
+            .iter()
+            .zip(tag.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(TagMismatch);
+        }
+        self.cipher.apply_keystream(nonce, 2, data);
+        Ok(())
+    }
+
+    fn tag(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+        let mut y = 0u128;
+        y = self.ghash_update(y, aad);
+        y = self.ghash_update(y, ciphertext);
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        y = gf_mul(y ^ lengths, self.h);
+        let mut tag = y.to_be_bytes();
+        self.cipher.apply_keystream(nonce, 1, &mut tag);
+        tag
+    }
+
+    fn ghash_update(&self, mut y: u128, data: &[u8]) -> u128 {
+        for chunk in data.chunks(BLOCK_SIZE) {
+            // the final partial block is padded with zeros
+            let mut block = [0u8; BLOCK_SIZE];
+            block[0..chunk.len()].copy_from_slice(chunk);
+            y = gf_mul(y ^ u128::from_be_bytes(block), self.h);
+        }
+        y
+    }
+}
+
+impl<C> fmt::Debug for Gcm<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gcm {{ ... }}")
+    }
+}
+
+// multiplication in GF(2^128) using the bit reflected convention of the gcm spec,
+// masks are used instead of branches so the run time doesn't depend on the operands
+fn gf_mul(x: u128, y: u128) -> u128 {
+    let r: u128 = 0xe1 << 120;
+    let mut z = 0u128;
+    let mut v = y;
+    for i in 0..128 {
+        let bit = (x >> (127 - i)) & 1;
+        z ^= v & 0u128.wrapping_sub(bit);
+        let lsb = v & 1;
+        v = (v >> 1) ^ (r & 0u128.wrapping_sub(lsb));
+    }
+    z
+}
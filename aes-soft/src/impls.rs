@@ -5,7 +5,7 @@ use crate::bitslice::{
 };
 use crate::consts::U32X4_0;
 use crate::simd::u32x4;
-use crate::{BlockCipher, NewBlockCipher};
+use crate::{BlockCipher, CtrMode, NewBlockCipher, BLOCK_SIZE, CTR_NONCE_SIZE};
 use crate::bitslice::{bit_slice_4x1_with_u16, un_bit_slice_4x1_with_u16, AesOps};
 use crate::consts::RCON;
 
@@ -148,7 +148,7 @@ macro_rules! define_aes_impl {
 
             #[inline]
             fn encrypt_block(&self, block: &mut [u8]) {
-                assert_eq!($key_size, block.len());
+                assert_eq!(BLOCK_SIZE, block.len());
                 let mut bs = bit_slice_1x16_with_u16(block);
                 bs = encrypt_core(&bs, &self.enc_keys);
                 un_bit_slice_1x16_with_u16(&bs, block);
@@ -156,7 +156,7 @@ macro_rules! define_aes_impl {
 
             #[inline]
             fn decrypt_block(&self, block: &mut [u8]) {
-                assert_eq!($key_size, block.len());
+                assert_eq!(BLOCK_SIZE, block.len());
                 let mut bs = bit_slice_1x16_with_u16(block);
                 bs = decrypt_core(&bs, &self.dec_keys);
                 un_bit_slice_1x16_with_u16(&bs, block);
@@ -164,7 +164,7 @@ macro_rules! define_aes_impl {
 
             #[inline]
             fn encrypt_blocks(&self, blocks: &mut [u8]) {
-                assert_eq!($key_size*8, blocks.len());
+                assert_eq!(BLOCK_SIZE * 8, blocks.len());
                 let bs = bit_slice_1x128_with_u32x4(blocks);
                 let bs2 = encrypt_core(&bs, &self.enc_keys8);
                 un_bit_slice_1x128_with_u32x4(bs2, blocks);
@@ -172,13 +172,37 @@ macro_rules! define_aes_impl {
 
             #[inline]
             fn decrypt_blocks(&self, blocks: &mut [u8]) {
-                assert_eq!($key_size*8, blocks.len());
+                assert_eq!(BLOCK_SIZE * 8, blocks.len());
                 let bs = bit_slice_1x128_with_u32x4(blocks);
                 let bs2 = decrypt_core(&bs, &self.dec_keys8);
                 un_bit_slice_1x128_with_u32x4(bs2, blocks);
             }
         }
 
+        impl CtrMode for $name {
+
+            #[inline]
+            fn apply_keystream(&self, nonce: &[u8], counter: u32, data: &mut [u8]) {
+                assert_eq!(CTR_NONCE_SIZE, nonce.len());
+                let mut counter = counter;
+                let mut keystream = [0u8; BLOCK_SIZE * 8];
+                // generate 8 counter blocks at a time so the bitsliced core is always fully used
+                for chunk in data.chunks_mut(BLOCK_SIZE * 8) {
+                    for block in keystream.chunks_mut(BLOCK_SIZE) {
+                        block[0..CTR_NONCE_SIZE].copy_from_slice(nonce);
+                        block[CTR_NONCE_SIZE..].copy_from_slice(&counter.to_be_bytes());
+                        counter = counter.wrapping_add(1);
+                    }
+                    let bs = bit_slice_1x128_with_u32x4(&keystream);
+                    let bs2 = encrypt_core(&bs, &self.enc_keys8);
+                    un_bit_slice_1x128_with_u32x4(bs2, &mut keystream);
+                    for (byte, key_byte) in chunk.iter_mut().zip(keystream.iter()) {
+                        *byte ^= key_byte;
+                    }
+                }
+            }
+        }
+
         opaque_debug::implement!($name);
     }
 }
@@ -1,6 +1,6 @@
 /*!
 ```
-    use aes_soft::{Aes128, BlockCipher, NewBlockCipher};
+    use aes_soft::{Aes128, BlockCipher, CtrMode, NewBlockCipher};
 
     // create aes key
     let mut key = Vec::with_capacity(16);
@@ -18,6 +18,14 @@
     assert_ne!(data, data_copy);
     aes.decrypt_blocks(&mut data);
     assert_eq!(data, data_copy);
+
+    // counter mode works on data of any length
+    let mut message = b"not a multiple of the block size".to_vec();
+    let nonce = [0u8; 12];
+    aes.apply_keystream(&nonce, 0, &mut message);
+    assert_ne!(&message[..], &b"not a multiple of the block size"[..]);
+    aes.apply_keystream(&nonce, 0, &mut message);
+    assert_eq!(&message[..], &b"not a multiple of the block size"[..]);
 ```
 !*/
 #![doc(html_logo_url = "https://raw.githubusercontent.com/RustCrypto/meta/master/logo_small.png")]
@@ -28,11 +36,18 @@
 
 mod bitslice;
 mod consts;
+mod gcm;
 mod impls;
 mod simd;
 
+pub use crate::gcm::{Aes128Gcm, Aes192Gcm, Aes256Gcm, Gcm, TagMismatch, TAG_SIZE};
 pub use crate::impls::{Aes128, Aes192, Aes256};
 
+/// size of a single aes block in bytes, regardless of key size
+pub const BLOCK_SIZE: usize = 16;
+/// size of the nonce used in counter mode, the remaining 4 bytes of each block are the counter
+pub const CTR_NONCE_SIZE: usize = 12;
+
 /// used as a trait to define creating new block ciphers
 pub trait NewBlockCipher {
     /// create new block cipher type, note for aes use 128 bit | 192 bit | 256 bit aka 16byte, 24byte, 32byte keys
@@ -41,12 +56,19 @@ pub trait NewBlockCipher {
 
 /// implemented on block cipher types, to define shared behavior of encryption and decryption
 pub trait BlockCipher {
-    /// encrypt 16/24/32 blocks at a time respectively
+    /// encrypt a single 16 byte block
     fn encrypt_block(&self, block: &mut [u8]);
-    /// decrypt 16/24/32 bytes at a time respectively
+    /// decrypt a single 16 byte block
     fn decrypt_block(&self, block: &mut [u8]);
-    /// encrypt 128/192/256 bytes at a time respectively
+    /// decrypt 8 blocks (128 bytes) at a time
     fn decrypt_blocks(&self, blocks: &mut [u8]);
-    /// decrypt 128/192/256 blocks at a time respectively
+    /// encrypt 8 blocks (128 bytes) at a time
     fn encrypt_blocks(&self, blocks: &mut [u8]);
+}
+
+/// counter mode, turns a block cipher into a stream cipher so data of any length can be encrypted in place
+pub trait CtrMode {
+    /// xor data with the keystream generated from the 12 byte nonce followed by a 32 bit big endian
+    /// block counter, beginning at counter. encryption and decryption are the same operation
+    fn apply_keystream(&self, nonce: &[u8], counter: u32, data: &mut [u8]);
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+//! counter mode tests, vectors are from NIST SP 800-38A appendix F.5
+use aes_soft::{Aes128, Aes192, Aes256, BlockCipher, CtrMode, NewBlockCipher};
+
+fn hex(input: &str) -> Vec<u8> {
+    let input: String = input.split_whitespace().collect();
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&input[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+const PLAINTEXT: &str = "6bc1bee22e409f96e93d7e117393172a ae2d8a571e03ac9c9eb76fac45af8e51
+    30c81c46a35ce411e5fbc1191a0a52ef f69f2445df4f9b17ad2b417be66c3710";
+// initial counter block f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff split into nonce and counter
+const NONCE: &str = "f0f1f2f3f4f5f6f7f8f9fafb";
+const COUNTER: u32 = 0xfcfdfeff;
+
+fn check_vector<C: CtrMode>(cipher: C, ciphertext: &str) {
+    let mut data = hex(PLAINTEXT);
+    cipher.apply_keystream(&hex(NONCE), COUNTER, &mut data);
+    assert_eq!(data, hex(ciphertext));
+    cipher.apply_keystream(&hex(NONCE), COUNTER, &mut data);
+    assert_eq!(data, hex(PLAINTEXT));
+}
+
+#[test]
+fn ctr_aes128() {
+    let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+    check_vector(
+        Aes128::new(&key),
+        "874d6191b620e3261bef6864990db6ce 9806f66b7970fdff8617187bb9fffdff
+         5ae4df3edbd5d35e5b4f09020db03eab 1e031dda2fbe03d1792170a0f3009cee",
+    );
+}
+
+#[test]
+fn ctr_aes192() {
+    let key = hex("8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b");
+    check_vector(
+        Aes192::new(&key),
+        "1abc932417521ca24f2b0459fe7e6e0b 090339ec0aa6faefd5ccc2c6f4ce8e94
+         1e36b26bd1ebc670d1bd1d665620abf7 4f78a7f6d29809585a97daec58c6b050",
+    );
+}
+
+#[test]
+fn ctr_aes256() {
+    let key = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+    check_vector(
+        Aes256::new(&key),
+        "601ec313775789a5b7a7f504bbf3d228 f443e3ca4d62b59aca84e990cacaf5c5
+         2b0930daa23de94ce87017ba2d84988d dfc9c58db67aada613c2dd08457941a6",
+    );
+}
+
+/// the 8 block keystream must match encrypting each counter block on its own, for any length
+#[test]
+fn ctr_matches_single_blocks() {
+    let aes = Aes128::new(&hex("2b7e151628aed2a6abf7158809cf4f3c"));
+    let nonce = hex(NONCE);
+    for len in &[0, 1, 15, 16, 17, 127, 128, 129, 1000] {
+        let mut data = vec![0u8; *len];
+        aes.apply_keystream(&nonce, 7, &mut data);
+        for (i, chunk) in data.chunks(16).enumerate() {
+            let mut block = [0u8; 16];
+            block[0..12].copy_from_slice(&nonce);
+            block[12..].copy_from_slice(&(7 + i as u32).to_be_bytes());
+            aes.encrypt_block(&mut block);
+            assert_eq!(chunk, &block[0..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! galois counter mode tests, vectors are from the NIST GCM specification test cases
+use aes_soft::{Aes128, Aes192, Aes256, BlockCipher, CtrMode, Gcm, NewBlockCipher, TagMismatch};
+
+fn hex(input: &str) -> Vec<u8> {
+    let input: String = input.split_whitespace().collect();
+    (0..input.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&input[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+const KEY: &str = "feffe9928665731c6d6a8f9467308308";
+const NONCE: &str = "cafebabefacedbaddecaf888";
+const PLAINTEXT: &str = "d9313225f88406e5a55909c5aff5269a 86a7a9531534f7da2e4c303d8a318a72
+    1c3c0c95956809532fcf0e2449a6b525 b16aedf5aa0de657ba637b391aafd255";
+const AAD: &str = "feedfacedeadbeeffeedfacedeadbeef abaddad2";
+
+fn check_vector<C: BlockCipher + CtrMode>(
+    cipher: C,
+    nonce: &str,
+    aad: &str,
+    plaintext: &str,
+    ciphertext: &str,
+    tag: &str,
+) {
+    let gcm = Gcm::new(cipher);
+    let mut data = hex(plaintext);
+    let out_tag = gcm.encrypt(&hex(nonce), &hex(aad), &mut data);
+    assert_eq!(data, hex(ciphertext));
+    assert_eq!(out_tag.to_vec(), hex(tag));
+    gcm.decrypt(&hex(nonce), &hex(aad), &mut data, &out_tag)
+        .unwrap();
+    assert_eq!(data, hex(plaintext));
+}
+
+#[test]
+fn gcm_aes128_empty() {
+    check_vector(
+        Aes128::new(&[0u8; 16]),
+        "000000000000000000000000",
+        "",
+        "",
+        "",
+        "58e2fccefa7e3061367f1d57a4e7455a",
+    );
+}
+
+#[test]
+fn gcm_aes128_zero_block() {
+    check_vector(
+        Aes128::new(&[0u8; 16]),
+        "000000000000000000000000",
+        "",
+        "00000000000000000000000000000000",
+        "0388dace60b6a392f328c2b971b2fe78",
+        "ab6e47d42cec13bdf53a67b21257bddf",
+    );
+}
+
+#[test]
+fn gcm_aes128_no_aad() {
+    check_vector(
+        Aes128::new(&hex(KEY)),
+        NONCE,
+        "",
+        PLAINTEXT,
+        "42831ec2217774244b7221b784d0d49c e3aa212f2c02a4e035c17e2329aca12e
+         21d514b25466931c7d8f6a5aac84aa05 1ba30b396a0aac973d58e091473f5985",
+        "4d5c2af327cd64a62cf35abd2ba6fab4",
+    );
+}
+
+#[test]
+fn gcm_aes128_aad() {
+    check_vector(
+        Aes128::new(&hex(KEY)),
+        NONCE,
+        AAD,
+        &PLAINTEXT[0..PLAINTEXT.len() - 8],
+        "42831ec2217774244b7221b784d0d49c e3aa212f2c02a4e035c17e2329aca12e
+         21d514b25466931c7d8f6a5aac84aa05 1ba30b396a0aac973d58e091",
+        "5bc94fbc3221a5db94fae95ae7121a47",
+    );
+}
+
+#[test]
+fn gcm_aes192_aad() {
+    check_vector(
+        Aes192::new(&hex("feffe9928665731c6d6a8f9467308308feffe9928665731c")),
+        NONCE,
+        AAD,
+        &PLAINTEXT[0..PLAINTEXT.len() - 8],
+        "3980ca0b3c00e841eb06fac4872a2757 859e1ceaa6efd984628593b40ca1e19c
+         7d773d00c144c525ac619d18c84a3f47 18e2448b2fe324d9ccda2710",
+        "2519498e80f1478f37ba55bd6d27618c",
+    );
+}
+
+#[test]
+fn gcm_aes256_zero_block() {
+    check_vector(
+        Aes256::new(&[0u8; 32]),
+        "000000000000000000000000",
+        "",
+        "00000000000000000000000000000000",
+        "cea7403d4d606b6e074ec5d3baf39d18",
+        "d0d1c8a799996bf0265b98b5d48ab919",
+    );
+}
+
+#[test]
+fn gcm_aes256_aad() {
+    check_vector(
+        Aes256::new(&hex(
+            "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+        )),
+        NONCE,
+        AAD,
+        &PLAINTEXT[0..PLAINTEXT.len() - 8],
+        "522dc1f099567d07f47f37a32a84427d 643a8cdcbfe5c0c97598a2bd2555d1aa
+         8cb08e48590dbb3da7b08b1056828838 c5f61e6393ba7a0abcc9f662",
+        "76fc6ece0f4e1768cddf8853bb2d551b",
+    );
+}
+
+#[test]
+fn gcm_rejects_tampering() {
+    let gcm = Gcm::new(Aes128::new(&hex(KEY)));
+    let mut data = hex(PLAINTEXT);
+    let tag = gcm.encrypt(&hex(NONCE), &hex(AAD), &mut data);
+    let ciphertext = data.clone();
+
+    data[3] ^= 0x80;
+    assert_eq!(
+        gcm.decrypt(&hex(NONCE), &hex(AAD), &mut data, &tag),
+        Err(TagMismatch)
+    );
+    let mut data = ciphertext.clone();
+    assert_eq!(
+        gcm.decrypt(&hex(NONCE), &hex("feedface"), &mut data, &tag),
+        Err(TagMismatch)
+    );
+    let mut bad_tag = tag;
+    bad_tag[15] ^= 1;
+    assert_eq!(
+        gcm.decrypt(&hex(NONCE), &hex(AAD), &mut data, &bad_tag),
+        Err(TagMismatch)
+    );
+    // a failed decryption leaves the data untouched
+    assert_eq!(data, ciphertext);
+}
@@ -1,26 +1,22 @@
-use verifyudp::{AsyncNetworkHost, AsyncSend};
+use std::error::Error;
+use std::net::SocketAddr;
 use verifyudp::utils::random_string;
 use verifyudp::SllpSocket;
-use verifyudp::L3Addr;
-use std::error::Error;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use verifyudp::{ArtificeConfig, AsyncNetworkHost, AsyncSend, RemotePeer};
+
+// written by sllp_server when it starts
+const PEER_FILE: &str = "sllp_peer.json";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let (mut peer, mut config) = test_config();
+    let peer: RemotePeer = serde_json::from_str(&std::fs::read_to_string(PEER_FILE)?)?;
     println!("peer addr: {}", peer.socket_addr());
-    // update default addr because only one udp socket per addr
-    config.set_socket_addr((L3Addr::newv4(0, 0, 0, 0), 3232).into());
-    println!("about to create socket");
+    // port 0 because only one udp socket per addr
+    let config = ArtificeConfig::generate(SocketAddr::from(([127, 0, 0, 1], 0)).into());
     let socket = SllpSocket::client_only(&config).await?;
-    peer.set_socket_addr(SocketAddr::new(
-        IpAddr::V4(Ipv4Addr::new(192, 168, 2, 5)),
-        6464,
-    ));
-    println!("created socket");
     let mut stream = socket.connect(&peer).await?;
-    println!("entering loop");
     loop {
-        stream.send(&random_string(65).into_bytes()).await.unwrap();
+        stream.send(&random_string(65).into_bytes()).await?;
+        tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
     }
 }
@@ -1,21 +1,44 @@
-use verifyudp::{AsyncNetworkHost, AsyncRecv};
-use verifyudp::SllpSocket;
-use verifyudp::{ConnectionRequest};
 use std::error::Error;
+use std::net::SocketAddr;
+use verifyudp::ConnectionRequest;
+use verifyudp::SllpSocket;
+use verifyudp::{ArtificeConfig, AsyncNetworkHost, AsyncRecv};
+
+// where sllp_client looks for the server identity
+const PEER_FILE: &str = "sllp_peer.json";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    
-    println!("config addr: {}", config.socket_addr());
+    let mut config = ArtificeConfig::generate(SocketAddr::from(([127, 0, 0, 1], 6464)).into());
+    // port 0 lets the os choose, the chosen port is advertised in the peer file
+    config.set_handshake_addr(SocketAddr::from(([127, 0, 0, 1], 0)));
     let mut socket = SllpSocket::from_host_config(&config).await?;
+    std::fs::write(PEER_FILE, serde_json::to_string(&socket.remote_peer())?)?;
+    println!(
+        "listening on {}, handshakes on {:?}",
+        socket.local_addr(),
+        socket.handshake_addr()
+    );
     while let Some(strm) = socket.incoming().await {
-        let mut stream = strm?.verify(&peer)?;
+        // the example accepts any client, a real server would verify against a PeerList
+        let mut stream = unsafe { strm?.unverify() };
         tokio::spawn(async move {
             println!("new connection");
             loop {
                 let mut invec = Vec::new();
-                stream.recv(&mut invec).await.unwrap();
-                println!("got message {}", String::from_utf8(invec).unwrap());
+                match stream.recv(&mut invec).await {
+                    // the client closed the stream
+                    Ok(lens) if lens.is_empty() => {
+                        println!("connection closed");
+                        break;
+                    }
+                    Ok(_) => println!("got message {}", String::from_utf8_lossy(&invec)),
+                    // a client that vanished times out rather than closing
+                    Err(e) => {
+                        println!("connection lost: {}", e);
+                        break;
+                    }
+                }
             }
         });
     }
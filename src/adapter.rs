@@ -0,0 +1,286 @@
+//! adapters for code written against tokio's AsyncRead and AsyncWrite, or the futures Sink and Stream.
+//! SllpIo turns the messages of a reliable stream into a byte stream, so it works with tokio::io::copy
+//! and framed codecs. SllpDatagrams sends and yields Bytes, one per message, for the other delivery modes.
+//! both wrap a whole SllpStream or one of its owned halves
+use crate::fragment::packet_count;
+use crate::protocol::{DeliveryMode, Message, StreamHeader, StreamStats};
+use crate::{
+    check_alive, check_send, IncomingMsg, NetworkError, OutgoingMsg, OwnedSllpReceiver,
+    OwnedSllpSender, Query, SendLimits, SllpStream,
+};
+use bytes::{Buf, Bytes};
+use futures::{ready, Sink, Stream};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// largest message a single write is sent as, SllpIo splits bigger writes
+pub const MAX_WRITE_LEN: usize = 64 * 1024;
+
+/// the sending side the adapters work with, see SllpStream and OwnedSllpSender
+pub trait PollSend {
+    /// ready once the session can take another message
+    fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), NetworkError>>;
+    /// hands a message to the session, only after poll_send_ready
+    fn start_send(&mut self, data: &[u8]) -> Result<(), NetworkError>;
+    /// sends nothing more, the session sends what is still waiting and then tells the peer.
+    /// what the peer sends in the meantime can still be received, until it acknowledges the close
+    fn close_send(&mut self);
+}
+/// the receiving side the adapters work with, see SllpStream and OwnedSllpReceiver
+pub trait PollRecv {
+    /// ready with None once the stream is closed
+    fn poll_recv_msg(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Message>, NetworkError>>;
+}
+/// how a stream delivers its messages, SllpIo only takes reliable ones
+pub trait Delivery {
+    fn delivery_mode(&self) -> DeliveryMode;
+}
+
+fn poll_ready(
+    sender: &mut Sender<OutgoingMsg>,
+    stats: &StreamStats,
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), NetworkError>> {
+    check_alive(stats)?;
+    sender.poll_ready(cx).map_err(|e| {
+        // the session may have given up on the peer
+        match check_alive(stats) {
+            Ok(()) => NetworkError::AsyncSendError(e.to_string()),
+            Err(dead) => dead,
+        }
+    })
+}
+fn start(
+    (sender, remote_addr): (&mut Sender<OutgoingMsg>, SocketAddr),
+    data: &[u8],
+    header: &StreamHeader,
+    limits: &SendLimits,
+    stats: &StreamStats,
+) -> Result<(), NetworkError> {
+    let packets = packet_count(header, data.len(), stats.mtu());
+    check_send(data.len(), limits, packets, stats)?;
+    sender
+        .try_send((data.to_vec(), remote_addr))
+        .map_err(|e| NetworkError::AsyncSendError(e.to_string()))
+}
+// the session closes the stream once every sender of it is gone,
+// this one is swapped for a sender whose receiver is already gone, so anything sent after fails
+fn close(sender: &mut Sender<OutgoingMsg>) {
+    *sender = channel(1).0;
+}
+fn poll_message(
+    receiver: &mut Receiver<IncomingMsg>,
+    stats: &StreamStats,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Option<Message>, NetworkError>> {
+    receiver.poll_recv(cx).map(|msg| match msg {
+        Some(result) => result.map(Some),
+        // the stream was closed, by this end or the peer, or the peer is gone
+        None => check_alive(stats).map(|_| None),
+    })
+}
+
+impl PollSend for SllpStream {
+    fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), NetworkError>> {
+        let (sender, _) = self.query.split();
+        poll_ready(sender, &self.stats, cx)
+    }
+    fn start_send(&mut self, data: &[u8]) -> Result<(), NetworkError> {
+        let (sender, _) = self.query.split();
+        let to = (sender, self.remote_addr);
+        start(to, data, &self.header, &self.limits, &self.stats)
+    }
+    fn close_send(&mut self) {
+        let (sender, _) = self.query.split();
+        close(sender);
+    }
+}
+impl PollRecv for SllpStream {
+    fn poll_recv_msg(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Message>, NetworkError>> {
+        let (_, receiver) = self.query.split();
+        poll_message(receiver, &self.stats, cx)
+    }
+}
+impl PollSend for OwnedSllpSender {
+    fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), NetworkError>> {
+        poll_ready(&mut self.sender, &self.stats, cx)
+    }
+    fn start_send(&mut self, data: &[u8]) -> Result<(), NetworkError> {
+        let to = (&mut self.sender, self.remote_addr);
+        start(to, data, &self.header, &self.limits, &self.stats)
+    }
+    fn close_send(&mut self) {
+        close(&mut self.sender);
+    }
+}
+impl PollRecv for OwnedSllpReceiver {
+    fn poll_recv_msg(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Message>, NetworkError>> {
+        poll_message(&mut self.receiver, &self.stats, cx)
+    }
+}
+impl Delivery for SllpStream {
+    fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
+    }
+}
+impl Delivery for OwnedSllpSender {
+    fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
+    }
+}
+impl Delivery for OwnedSllpReceiver {
+    fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
+    }
+}
+
+fn io_error(error: NetworkError) -> io::Error {
+    match error {
+        NetworkError::IOError(e) => e,
+        error => io::Error::other(error.to_string()),
+    }
+}
+
+/// AsyncRead and AsyncWrite over a stream in DeliveryMode::Reliable, every write is sent as a message
+/// and reads go through the messages as they come. over the other modes bytes could go missing
+/// or come out of order, so they are refused, SllpDatagrams suits those
+#[derive(Debug)]
+pub struct SllpIo<T> {
+    inner: T,
+    // what is left of a message that didn't fit the last read
+    buffered: Bytes,
+}
+impl<T: Delivery> SllpIo<T> {
+    /// fails for a stream that isn't in DeliveryMode::Reliable
+    pub fn new(inner: T) -> Result<Self, NetworkError> {
+        if inner.delivery_mode() != DeliveryMode::Reliable {
+            return Err(NetworkError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SllpIo needs a reliable stream",
+            )));
+        }
+        Ok(Self {
+            inner,
+            buffered: Bytes::new(),
+        })
+    }
+}
+impl<T> SllpIo<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    /// anything read from a message but not returned yet is lost
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T: PollRecv + Unpin> AsyncRead for SllpIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // empty messages would read as the end of the stream, so they are skipped
+        while this.buffered.is_empty() {
+            match ready!(this.inner.poll_recv_msg(cx)).map_err(io_error)? {
+                Some(message) => this.buffered = Bytes::from(message.into_data()),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let len = buf.len().min(this.buffered.len());
+        buf[..len].copy_from_slice(&this.buffered[..len]);
+        this.buffered.advance(len);
+        Poll::Ready(Ok(len))
+    }
+}
+impl<T: PollSend + Unpin> AsyncWrite for SllpIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        ready!(this.inner.poll_send_ready(cx)).map_err(io_error)?;
+        let len = buf.len().min(MAX_WRITE_LEN);
+        this.inner.start_send(&buf[..len]).map_err(io_error)?;
+        Poll::Ready(Ok(len))
+    }
+    // a written message is already with the session, which sends it as soon as it can
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    // the peer reads the end of the stream once it has everything written before,
+    // reads go on until then, and end with an error if it never acknowledges
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.close_send();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// a Sink and a Stream of Bytes, one per message, for streams that aren't reliable,
+/// or whenever where the messages start and end matters
+#[derive(Debug)]
+pub struct SllpDatagrams<T> {
+    inner: T,
+}
+impl<T> SllpDatagrams<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+impl<T: PollRecv + Unpin> Stream for SllpDatagrams<T> {
+    type Item = Result<Bytes, NetworkError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.inner.poll_recv_msg(cx).map(|result| {
+            result
+                .map(|message| message.map(|message| Bytes::from(message.into_data())))
+                .transpose()
+        })
+    }
+}
+impl<T: PollSend + Unpin> Sink<Bytes> for SllpDatagrams<T> {
+    type Error = NetworkError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), NetworkError>> {
+        self.get_mut().inner.poll_send_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), NetworkError> {
+        self.get_mut().inner.start_send(&item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), NetworkError>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), NetworkError>> {
+        self.get_mut().inner.close_send();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,522 @@
+//! a stream carries channels, each with its own delivery mode and priority, so one handshake serves
+//! several kinds of traffic. their packets are of type Channel, the payload is the channel's header
+//! followed by the RawData or RawDataAck it carries. channel 0 is the stream itself, its packets aren't wrapped.
+//! either end opens a channel just by sending on it, the peer picks it up with SllpStream::accept_channel
+use crate::congestion::CongestionAlgorithm;
+use crate::fragment::packet_count;
+use crate::protocol::{DeliveryMode, Message, PacketType, StreamHeader, StreamStats};
+use crate::reliable::{discard, segment_count, Reliable};
+use crate::session::Link;
+use crate::{
+    recv_message, recv_packet, send_packet, AsyncQuery, AsyncRecv, AsyncSend, IncomingMsg,
+    NetworkError, OutgoingMsg, Query, SendLimits, STREAM_CHANNEL_LEN,
+};
+use async_trait::async_trait;
+use futures::future::poll_fn;
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Poll;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::Instant;
+
+pub const CHANNEL_HEADER_LEN: usize = 7;
+/// the priority of the stream's own messages, those of channels with a higher one go out first
+pub const STREAM_PRIORITY: u8 = 128;
+
+/// id u32 | delivery mode u8 | priority u8, followed by the type of the packet carried.
+/// every packet says how its channel is set up, so the peer can open it from whichever arrives first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelInfo {
+    id: u32,
+    mode: DeliveryMode,
+    priority: u8,
+}
+impl ChannelInfo {
+    pub fn new(id: u32, mode: DeliveryMode, priority: u8) -> Self {
+        Self { id, mode, priority }
+    }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn mode(&self) -> DeliveryMode {
+        self.mode
+    }
+    /// messages of channels with a higher priority are sent first
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+    pub fn to_raw(&self, packet_type: PacketType) -> Vec<u8> {
+        let mut outvec = Vec::with_capacity(CHANNEL_HEADER_LEN);
+        outvec.extend_from_slice(&self.id.to_be_bytes());
+        outvec.push(mode_to_u8(self.mode));
+        outvec.push(self.priority);
+        outvec.push(packet_type.to_u8().unwrap_or_default());
+        outvec
+    }
+    pub fn from_raw(data: &[u8]) -> Result<(Self, PacketType), NetworkError> {
+        let invalid = || NetworkError::ConnectionDenied("invalid channel header".to_string());
+        if data.len() < CHANNEL_HEADER_LEN {
+            return Err(invalid());
+        }
+        let id = u32::from_be_bytes(data[0..4].try_into()?);
+        let mode = mode_from_u8(data[4]).ok_or_else(invalid)?;
+        let packet_type = FromPrimitive::from_u8(data[6]).ok_or_else(invalid)?;
+        Ok((Self::new(id, mode, data[5]), packet_type))
+    }
+}
+fn mode_to_u8(mode: DeliveryMode) -> u8 {
+    match mode {
+        DeliveryMode::Unordered => 0,
+        DeliveryMode::Reliable => 1,
+        DeliveryMode::LatestOnly => 2,
+    }
+}
+fn mode_from_u8(byte: u8) -> Option<DeliveryMode> {
+    match byte {
+        0 => Some(DeliveryMode::Unordered),
+        1 => Some(DeliveryMode::Reliable),
+        2 => Some(DeliveryMode::LatestOnly),
+        _ => None,
+    }
+}
+
+/// a channel the stream opened, and the session's end of it
+pub type OpenedChannel = (ChannelInfo, AsyncQuery<IncomingMsg, OutgoingMsg>);
+/// a channel the peer opened, and the stream's end of it
+pub type AcceptedChannel = (ChannelInfo, AsyncQuery<OutgoingMsg, IncomingMsg>);
+
+fn closed() -> NetworkError {
+    NetworkError::IOError(std::io::Error::new(
+        std::io::ErrorKind::NotConnected,
+        "the stream is closed",
+    ))
+}
+
+/// how a stream reaches the channels of its session
+#[derive(Debug)]
+pub struct ChannelHandle {
+    opened: Sender<OpenedChannel>,
+    accepted: Receiver<AcceptedChannel>,
+    // each end opens channels with ids of its own parity, so they never pick the same one
+    next_id: u32,
+}
+impl ChannelHandle {
+    /// a handle without a session, opening and accepting fail as they do once the stream is closed
+    pub fn closed() -> Self {
+        let (opened, _) = channel(1);
+        let (_, accepted) = channel(1);
+        Self {
+            opened,
+            accepted,
+            next_id: 1,
+        }
+    }
+    pub async fn open(
+        &mut self,
+        mode: DeliveryMode,
+        priority: u8,
+    ) -> Result<AcceptedChannel, NetworkError> {
+        let info = ChannelInfo::new(self.next_id, mode, priority);
+        let (app_end, session_end) = channel_ends();
+        self.opened
+            .send((info, session_end))
+            .await
+            .map_err(|_| closed())?;
+        self.next_id += 2;
+        Ok((info, app_end))
+    }
+    pub async fn accept(&mut self) -> Result<AcceptedChannel, NetworkError> {
+        self.accepted.recv().await.ok_or_else(closed)
+    }
+}
+fn channel_ends() -> (
+    AsyncQuery<OutgoingMsg, IncomingMsg>,
+    AsyncQuery<IncomingMsg, OutgoingMsg>,
+) {
+    let (app_sender, from_app) = channel(STREAM_CHANNEL_LEN);
+    let (to_app, app_receiver) = channel(STREAM_CHANNEL_LEN);
+    (
+        AsyncQuery::create(app_sender, app_receiver),
+        AsyncQuery::create(to_app, from_app),
+    )
+}
+
+/// a channel of a stream, see SllpStream::open_channel. it shares the key, the peer and the send limits
+/// of the stream, and stops sending once dropped, the peer's end stays open until the stream closes
+#[derive(Debug)]
+pub struct SllpChannel {
+    info: ChannelInfo,
+    header: StreamHeader,
+    remote_addr: SocketAddr,
+    query: AsyncQuery<OutgoingMsg, IncomingMsg>,
+    stats: Arc<StreamStats>,
+    limits: SendLimits,
+}
+impl SllpChannel {
+    pub fn new(
+        (info, query): AcceptedChannel,
+        header: StreamHeader,
+        remote_addr: SocketAddr,
+        stats: Arc<StreamStats>,
+        limits: SendLimits,
+    ) -> Self {
+        Self {
+            info,
+            header,
+            remote_addr,
+            query,
+            stats,
+            limits,
+        }
+    }
+    pub fn info(&self) -> &ChannelInfo {
+        &self.info
+    }
+    /// those of the stream, which the channel shares
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+    /// the next message, with what its packet said about it, None once the stream is closed
+    pub async fn recv_msg(&mut self) -> Result<Option<Message>, NetworkError> {
+        let (_, receiver) = self.query.split();
+        recv_message(receiver, &self.stats).await
+    }
+}
+#[async_trait]
+impl AsyncSend for SllpChannel {
+    type SendError = NetworkError;
+    async fn send(&mut self, inbuf: &[u8]) -> Result<usize, NetworkError> {
+        let mtu = self.stats.mtu();
+        let packets = match self.info.mode {
+            DeliveryMode::Reliable => {
+                segment_count(&self.header, inbuf.len(), mtu, CHANNEL_HEADER_LEN)
+            }
+            _ => packet_count(&self.header, inbuf.len() + CHANNEL_HEADER_LEN, mtu),
+        };
+        let (sender, _) = self.query.split();
+        send_packet(
+            sender,
+            self.remote_addr,
+            inbuf,
+            &self.limits,
+            packets,
+            &self.stats,
+        )
+        .await
+    }
+    fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+}
+#[async_trait]
+impl AsyncRecv for SllpChannel {
+    type RecvError = NetworkError;
+    async fn recv(&mut self, outbuf: &mut Vec<u8>) -> Result<Vec<usize>, NetworkError> {
+        let (_, receiver) = self.query.split();
+        recv_packet(receiver, outbuf, &self.stats).await
+    }
+    fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+}
+
+// the session's side of a channel
+#[derive(Debug)]
+struct Channel {
+    info: ChannelInfo,
+    to_app: Sender<IncomingMsg>,
+    // sequence number of the last message delivered, for latest only channels
+    latest: u64,
+    reliable: Reliable,
+    // nothing sends on it anymore, it goes once what it sent has been acknowledged
+    closed: bool,
+}
+impl Channel {
+    async fn on_data(
+        &mut self,
+        link: &mut Link,
+        seq: u64,
+        payload: Vec<u8>,
+    ) -> Result<(), NetworkError> {
+        match self.info.mode {
+            DeliveryMode::Reliable => return self.reliable.on_data(link, seq, payload).await,
+            DeliveryMode::LatestOnly if seq < self.latest => {
+                link.stats().add_stale();
+                return Ok(());
+            }
+            DeliveryMode::LatestOnly => self.latest = seq,
+            DeliveryMode::Unordered => (),
+        }
+        let message = Message::new(payload, PacketType::RawData, seq, Instant::now().into_std());
+        // a channel nothing reads from anymore drops what arrives
+        let _ = self.to_app.try_send(Ok(message));
+        Ok(())
+    }
+}
+
+/// where the messages sent on a channel come from, see next_input
+#[derive(Debug)]
+pub struct ChannelInput {
+    info: ChannelInfo,
+    from_app: Receiver<OutgoingMsg>,
+}
+impl ChannelInput {
+    /// for the stream's own messages, which are scheduled along with those of its channels
+    pub fn new(info: ChannelInfo, from_app: Receiver<OutgoingMsg>) -> Self {
+        Self { info, from_app }
+    }
+    pub fn info(&self) -> &ChannelInfo {
+        &self.info
+    }
+}
+
+/// the channels of a stream, run by its session
+#[derive(Debug)]
+pub struct Channels {
+    open: HashMap<u32, Channel>,
+    accepted: Sender<AcceptedChannel>,
+    // the ids the peer opens channels with are all odd, or all even
+    peer_parity: u32,
+    // ids of the peer's channels that were closed, those below retired_below and those in retired,
+    // so their late packets don't open them again
+    retired_below: u32,
+    retired: BTreeSet<u32>,
+    congestion: CongestionAlgorithm,
+    // those the session hasn't taken yet, see take_inputs
+    inputs: Vec<ChannelInput>,
+}
+impl Channels {
+    /// the session receives the channels the stream opens on the returned receiver
+    /// initiator is true for the end that started the handshake
+    pub fn new(
+        initiator: bool,
+        congestion: CongestionAlgorithm,
+    ) -> (Self, Receiver<OpenedChannel>, ChannelHandle) {
+        let (opened, from_stream) = channel(STREAM_CHANNEL_LEN);
+        let (accepted, to_stream) = channel(STREAM_CHANNEL_LEN);
+        // the end that started the handshake takes the odd ids, the other the even ones
+        let (first_id, peer_first_id) = if initiator { (1, 2) } else { (2, 1) };
+        let channels = Self {
+            open: HashMap::new(),
+            accepted,
+            peer_parity: peer_first_id % 2,
+            retired_below: peer_first_id,
+            retired: BTreeSet::new(),
+            congestion,
+            inputs: Vec::new(),
+        };
+        let handle = ChannelHandle {
+            opened,
+            accepted: to_stream,
+            next_id: first_id,
+        };
+        (channels, from_stream, handle)
+    }
+    pub fn add(&mut self, (info, query): OpenedChannel) {
+        let (to_app, from_app) = query.into_split();
+        let reliable = Reliable::for_channel(self.congestion.build(), info);
+        let channel = Channel {
+            info,
+            to_app,
+            latest: 0,
+            reliable,
+            closed: false,
+        };
+        self.open.insert(info.id, channel);
+        self.inputs.push(ChannelInput { info, from_app });
+    }
+    /// the inputs of channels added since the last call
+    pub fn take_inputs(&mut self) -> Vec<ChannelInput> {
+        std::mem::take(&mut self.inputs)
+    }
+    /// nothing sends on the channel anymore, see drop_closed
+    pub fn close(&mut self, id: u32) {
+        if let Some(channel) = self.open.get_mut(&id) {
+            channel.closed = true;
+        }
+    }
+    /// forgets the closed channels once everything they sent has been acknowledged
+    pub fn drop_closed(&mut self) {
+        let done: Vec<u32> = self
+            .open
+            .values()
+            .filter(|channel| channel.closed && channel.reliable.is_idle())
+            .map(|channel| channel.info.id)
+            .collect();
+        for id in done {
+            self.open.remove(&id);
+            if id % 2 == self.peer_parity {
+                self.retired.insert(id);
+            }
+        }
+        while self.retired.remove(&self.retired_below) {
+            self.retired_below += 2;
+        }
+    }
+    fn is_retired(&self, id: u32) -> bool {
+        id < self.retired_below || self.retired.contains(&id)
+    }
+    /// reliable channels that have to wait for acknowledgements before they send more
+    pub fn blocked(&self) -> Vec<u32> {
+        self.open
+            .values()
+            .filter(|channel| channel.info.mode == DeliveryMode::Reliable)
+            .filter(|channel| !channel.reliable.window_open())
+            .map(|channel| channel.info.id)
+            .collect()
+    }
+    /// everything sent on the reliable channels has been acknowledged
+    pub fn is_idle(&self) -> bool {
+        self.open.values().all(|channel| channel.reliable.is_idle())
+    }
+    pub fn deadline(&self) -> Option<Instant> {
+        self.open
+            .values()
+            .filter_map(|channel| channel.reliable.deadline())
+            .min()
+    }
+    pub async fn on_timeout(&mut self, link: &mut Link) -> Result<(), NetworkError> {
+        for channel in self.open.values_mut() {
+            channel.reliable.on_timeout(link).await?;
+        }
+        Ok(())
+    }
+    pub fn deliver(&mut self) {
+        for channel in self.open.values_mut() {
+            channel.reliable.deliver(&mut channel.to_app);
+        }
+    }
+    pub async fn on_app(
+        &mut self,
+        link: &mut Link,
+        id: u32,
+        data: Vec<u8>,
+    ) -> Result<(), NetworkError> {
+        let channel = match self.open.get_mut(&id) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        match channel.info.mode {
+            DeliveryMode::Reliable => channel.reliable.send_new(link, data).await,
+            DeliveryMode::Unordered | DeliveryMode::LatestOnly => {
+                link.send_on(Some(&channel.info), PacketType::RawData, &data)
+                    .await
+            }
+        }
+    }
+    /// payload is that of a Channel packet, seq is that of the packet that completed it
+    pub async fn on_packet(
+        &mut self,
+        link: &mut Link,
+        seq: u64,
+        payload: &[u8],
+    ) -> Result<(), NetworkError> {
+        let (info, packet_type) = match ChannelInfo::from_raw(payload) {
+            Ok(header) => header,
+            Err(_) => return Ok(()),
+        };
+        let payload = payload[CHANNEL_HEADER_LEN..].to_vec();
+        // data on a channel that isn't open yet opens it, if the id is one the peer may pick
+        let peers = info.id != 0 && info.id % 2 == self.peer_parity && !self.is_retired(info.id);
+        if !self.open.contains_key(&info.id) && peers && packet_type == PacketType::RawData {
+            let (app_end, session_end) = channel_ends();
+            self.add((info, session_end));
+            // a stream that doesn't accept its channels loses them, like messages it doesn't read
+            let _ = self.accepted.try_send((info, app_end));
+        }
+        let channel = match self.open.get_mut(&info.id) {
+            Some(channel) => channel,
+            // the channel was closed, the peer is told it has what it sent so it stops resending
+            None if info.mode == DeliveryMode::Reliable && packet_type == PacketType::RawData => {
+                return discard(link, &info, &payload).await
+            }
+            None => return Ok(()),
+        };
+        match packet_type {
+            PacketType::RawData => channel.on_data(link, seq, payload).await,
+            PacketType::RawDataAck => channel.reliable.on_ack(link, &payload).await,
+            _ => Ok(()),
+        }
+    }
+    /// hands over what reliable channels received in order, for once the peer is gone
+    pub async fn finish(&mut self) {
+        for channel in self.open.values_mut() {
+            for message in channel.reliable.take_ready() {
+                if channel.to_app.send(Ok(message)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// the next message of any channel that isn't blocked, by priority, and among channels of the same
+/// priority in turn. None with the id means nothing sends on that channel anymore
+pub async fn next_input(inputs: &mut [ChannelInput], blocked: &[u32]) -> (u32, Option<Vec<u8>>) {
+    poll_fn(|cx| {
+        for i in 0..inputs.len() {
+            let info = inputs[i].info;
+            if blocked.contains(&info.id) {
+                continue;
+            }
+            if let Poll::Ready(msg) = inputs[i].from_app.poll_recv(cx) {
+                // to the back of those with its priority
+                let end = inputs[i..]
+                    .iter()
+                    .position(|input| input.info.priority != info.priority)
+                    .map_or(inputs.len(), |len| i + len);
+                inputs[i..end].rotate_left(1);
+                return Poll::Ready((info.id, msg.map(|(data, _)| data)));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+/// adds new inputs, keeping them ordered from the highest priority down
+pub fn add_inputs(inputs: &mut Vec<ChannelInput>, new: Vec<ChannelInput>) {
+    if new.is_empty() {
+        return;
+    }
+    inputs.extend(new);
+    inputs.sort_by_key(|input| std::cmp::Reverse(input.info.priority));
+}
+
+#[test]
+fn channel_info_raw_test() {
+    let info = ChannelInfo::new(7, DeliveryMode::LatestOnly, 3);
+    let raw = info.to_raw(PacketType::RawDataAck);
+    assert_eq!(raw.len(), CHANNEL_HEADER_LEN);
+    assert_eq!(
+        ChannelInfo::from_raw(&raw).unwrap(),
+        (info, PacketType::RawDataAck)
+    );
+    assert!(ChannelInfo::from_raw(&raw[1..]).is_err());
+    let mut unknown_mode = raw;
+    unknown_mode[4] = 9;
+    assert!(ChannelInfo::from_raw(&unknown_mode).is_err());
+}
+#[test]
+fn closed_channels_are_dropped() {
+    let (_, _, handle) = Channels::new(true, CongestionAlgorithm::default());
+    assert_eq!(handle.next_id, 1);
+    let (mut channels, _, handle) = Channels::new(false, CongestionAlgorithm::default());
+    assert_eq!(handle.next_id, 2);
+    for id in [1, 3, 5] {
+        let (_, session_end) = channel_ends();
+        let info = ChannelInfo::new(id, DeliveryMode::Reliable, 0);
+        channels.add((info, session_end));
+    }
+    channels.close(1);
+    channels.close(5);
+    channels.drop_closed();
+    assert_eq!(channels.open.len(), 1);
+    assert!(channels.is_retired(1) && !channels.is_retired(3) && channels.is_retired(5));
+    channels.close(3);
+    channels.drop_closed();
+    assert!(channels.open.is_empty());
+    assert_eq!((channels.retired_below, channels.retired.len()), (7, 0));
+}
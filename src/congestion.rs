@@ -0,0 +1,294 @@
+//! congestion control for reliable streams, fed by their acknowledgements.
+//! the window limits the bytes a stream has in flight, the pacing rate spaces its datagrams out
+use crate::pmtu::BASE_MTU;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// the unit windows are counted in
+const MSS: usize = BASE_MTU;
+const INITIAL_WINDOW: usize = 10 * MSS;
+const MIN_WINDOW: usize = 2 * MSS;
+
+/// decides how fast a stream may send, see NewReno and Bbr
+pub trait CongestionControl: Debug + Send {
+    /// bytes that may be unacknowledged at once
+    fn window(&self) -> usize;
+    /// bytes per second to space datagrams out to, None sends them as they come
+    fn pacing_rate(&self) -> Option<f64>;
+    fn on_sent(&mut self, bytes: usize, now: Instant);
+    /// bytes newly acknowledged, rtt is None if it couldn't be measured, as for retransmissions
+    fn on_ack(&mut self, bytes: usize, rtt: Option<Duration>, now: Instant);
+    /// acknowledgements skipped over a message, so it was most likely lost
+    fn on_loss(&mut self, now: Instant);
+    /// the retransmission timer ran out, nothing got through for a while
+    fn on_timeout(&mut self, now: Instant);
+}
+
+/// which CongestionControl the streams of a socket use
+#[derive(
+    Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Default,
+)]
+pub enum CongestionAlgorithm {
+    /// loss based, as in rfc 6582
+    #[default]
+    NewReno,
+    /// model based, paces at the measured bottleneck bandwidth rather than waiting for losses
+    Bbr,
+}
+impl CongestionAlgorithm {
+    pub fn build(self) -> Box<dyn CongestionControl> {
+        match self {
+            Self::NewReno => Box::new(NewReno::default()),
+            Self::Bbr => Box::new(Bbr::default()),
+        }
+    }
+}
+
+/// slow start, then one segment more per round trip, and half the window on a loss
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    cwnd: usize,
+    ssthresh: usize,
+    srtt: Option<Duration>,
+    // losses until then belong to the loss the window was already cut for
+    recovery_until: Option<Instant>,
+}
+impl Default for NewReno {
+    fn default() -> Self {
+        Self {
+            cwnd: INITIAL_WINDOW,
+            ssthresh: usize::MAX,
+            srtt: None,
+            recovery_until: None,
+        }
+    }
+}
+impl NewReno {
+    fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+    fn cut(&mut self) {
+        self.ssthresh = (self.cwnd / 2).max(MIN_WINDOW);
+    }
+}
+impl CongestionControl for NewReno {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+    fn pacing_rate(&self) -> Option<f64> {
+        // a little ahead of the window, so pacing alone never holds the stream back, as linux does
+        let gain = if self.in_slow_start() { 2.0 } else { 1.25 };
+        let srtt = self.srtt?.as_secs_f64().max(0.001);
+        Some(gain * self.cwnd as f64 / srtt)
+    }
+    fn on_sent(&mut self, _bytes: usize, _now: Instant) {}
+    fn on_ack(&mut self, bytes: usize, rtt: Option<Duration>, _now: Instant) {
+        if let Some(rtt) = rtt {
+            self.srtt = Some(match self.srtt {
+                Some(srtt) => srtt * 7 / 8 + rtt / 8,
+                None => rtt,
+            });
+        }
+        if self.in_slow_start() {
+            self.cwnd += bytes;
+        } else {
+            self.cwnd += (MSS * bytes / self.cwnd).max(1);
+        }
+    }
+    fn on_loss(&mut self, now: Instant) {
+        if self.recovery_until.is_some_and(|until| now < until) {
+            return;
+        }
+        self.cut();
+        self.cwnd = self.ssthresh;
+        self.recovery_until = Some(now + self.srtt.unwrap_or(Duration::from_secs(1)));
+    }
+    fn on_timeout(&mut self, _now: Instant) {
+        self.cut();
+        self.cwnd = MSS;
+    }
+}
+
+const STARTUP_GAIN: f64 = 2.885;
+/// pacing gains of probe bandwidth, one round each, probing for more then draining the queue that made
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// rounds the bottleneck bandwidth is the highest rate from
+const BW_ROUNDS: u64 = 10;
+/// the minimum rtt is measured again after this long
+const MIN_RTT_LIFETIME: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BbrState {
+    /// doubles the rate every round until the bandwidth stops growing
+    Startup,
+    /// empties the queue startup built up
+    Drain,
+    ProbeBw(usize),
+}
+
+/// a simplified bbr: the delivery rate of every round trip feeds a windowed maximum,
+/// the bottleneck bandwidth, and the smallest rtt seen is the propagation delay.
+/// it paces at the bandwidth, and allows twice their product in flight
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    state: BbrState,
+    // delivery rates of recent rounds, in bytes per second
+    bw_samples: VecDeque<(u64, f64)>,
+    min_rtt: Option<(Duration, Instant)>,
+    round: u64,
+    round_start: Option<Instant>,
+    round_delivered: usize,
+    // startup ends once three rounds in a row fail to grow the bandwidth by a quarter
+    full_bw: f64,
+    full_bw_rounds: u32,
+}
+impl Default for Bbr {
+    fn default() -> Self {
+        Self {
+            state: BbrState::Startup,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            round: 0,
+            round_start: None,
+            round_delivered: 0,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+        }
+    }
+}
+impl Bbr {
+    fn btl_bw(&self) -> Option<f64> {
+        self.bw_samples
+            .iter()
+            .map(|(_, bw)| *bw)
+            .fold(None, |max, bw| Some(max.map_or(bw, |max: f64| max.max(bw))))
+    }
+    fn pacing_gain(&self) -> f64 {
+        match self.state {
+            BbrState::Startup => STARTUP_GAIN,
+            BbrState::Drain => 1.0 / STARTUP_GAIN,
+            BbrState::ProbeBw(cycle) => PROBE_BW_GAINS[cycle],
+        }
+    }
+    fn end_round(&mut self, now: Instant, elapsed: Duration) {
+        self.round += 1;
+        let bw = self.round_delivered as f64 / elapsed.as_secs_f64().max(0.000_001);
+        self.round_delivered = 0;
+        self.round_start = Some(now);
+        let round = self.round;
+        self.bw_samples
+            .retain(|(sampled, _)| round - sampled < BW_ROUNDS);
+        self.bw_samples.push_back((round, bw));
+        let btl_bw = self.btl_bw().unwrap_or(bw);
+        self.state = match self.state {
+            BbrState::Startup => {
+                if btl_bw >= self.full_bw * 1.25 {
+                    self.full_bw = btl_bw;
+                    self.full_bw_rounds = 0;
+                } else {
+                    self.full_bw_rounds += 1;
+                }
+                if self.full_bw_rounds >= 3 {
+                    BbrState::Drain
+                } else {
+                    BbrState::Startup
+                }
+            }
+            BbrState::Drain => BbrState::ProbeBw(0),
+            BbrState::ProbeBw(cycle) => BbrState::ProbeBw((cycle + 1) % PROBE_BW_GAINS.len()),
+        };
+    }
+}
+impl CongestionControl for Bbr {
+    fn window(&self) -> usize {
+        match (self.btl_bw(), self.min_rtt) {
+            (Some(bw), Some((rtt, _))) => {
+                let gain = if self.state == BbrState::Startup {
+                    STARTUP_GAIN
+                } else {
+                    2.0
+                };
+                ((gain * bw * rtt.as_secs_f64()) as usize).max(4 * MSS)
+            }
+            _ => INITIAL_WINDOW,
+        }
+    }
+    fn pacing_rate(&self) -> Option<f64> {
+        Some(self.pacing_gain() * self.btl_bw()?)
+    }
+    fn on_sent(&mut self, _bytes: usize, now: Instant) {
+        if self.round_start.is_none() {
+            self.round_start = Some(now);
+        }
+    }
+    fn on_ack(&mut self, bytes: usize, rtt: Option<Duration>, now: Instant) {
+        if let Some(rtt) = rtt {
+            let replace = match self.min_rtt {
+                Some((min, stamp)) => rtt <= min || now - stamp > MIN_RTT_LIFETIME,
+                None => true,
+            };
+            if replace {
+                self.min_rtt = Some((rtt, now));
+            }
+        }
+        self.round_delivered += bytes;
+        let round_start = *self.round_start.get_or_insert(now);
+        let elapsed = now - round_start;
+        if let Some((min_rtt, _)) = self.min_rtt {
+            if elapsed >= min_rtt {
+                self.end_round(now, elapsed);
+            }
+        }
+    }
+    // losses don't change the model, the bandwidth samples already show what got through
+    fn on_loss(&mut self, _now: Instant) {}
+    fn on_timeout(&mut self, _now: Instant) {
+        // start over, the path may have changed entirely
+        *self = Self::default();
+    }
+}
+
+#[test]
+fn new_reno_test() {
+    let now = Instant::now();
+    let mut reno = NewReno::default();
+    assert_eq!(reno.window(), INITIAL_WINDOW);
+    assert_eq!(reno.pacing_rate(), None);
+    // slow start doubles the window every round trip
+    reno.on_ack(INITIAL_WINDOW, Some(Duration::from_millis(100)), now);
+    assert_eq!(reno.window(), 2 * INITIAL_WINDOW);
+    assert!(reno.pacing_rate().is_some());
+    // one loss per round trip halves it, later losses in the same round trip don't
+    reno.on_loss(now);
+    assert_eq!(reno.window(), INITIAL_WINDOW);
+    reno.on_loss(now + Duration::from_millis(10));
+    assert_eq!(reno.window(), INITIAL_WINDOW);
+    // congestion avoidance adds about one segment per window acknowledged
+    reno.on_ack(INITIAL_WINDOW, None, now);
+    assert_eq!(reno.window(), INITIAL_WINDOW + MSS);
+    reno.on_timeout(now);
+    assert_eq!(reno.window(), MSS);
+}
+#[test]
+fn bbr_test() {
+    let mut now = Instant::now();
+    let mut bbr = Bbr::default();
+    assert_eq!(bbr.window(), INITIAL_WINDOW);
+    assert_eq!(bbr.pacing_rate(), None);
+    // a path of 1 MB/s with 50ms of delay, acknowledgements come in every 10ms
+    let rtt = Duration::from_millis(50);
+    let step = Duration::from_millis(10);
+    bbr.on_sent(10_000, now);
+    for _ in 0..200 {
+        now += step;
+        bbr.on_ack(10_000, Some(rtt), now);
+    }
+    let bw = bbr.btl_bw().unwrap();
+    assert!(bw > 900_000.0 && bw < 1_100_000.0);
+    // out of startup, the window is about twice the bandwidth delay product
+    assert!(matches!(bbr.state, BbrState::ProbeBw(_)));
+    assert!(bbr.window() > 90_000 && bbr.window() < 110_000);
+    bbr.on_timeout(now);
+    assert_eq!(bbr.window(), INITIAL_WINDOW);
+}
@@ -0,0 +1,360 @@
+//! messages too big for one datagram are split into fragments, packets of type Fragment,
+//! each payload starts with a FragmentHeader, and the receiver puts them back together
+use crate::protocol::{ConnectionId, PacketType, SeqCounter, StreamHeader, StreamStats};
+use crate::{seal, seal_overhead, NetworkError};
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// largest message a stream sends or accepts
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+/// bytes of partly received messages a stream holds on to, the longest quiet are dropped to make room
+pub const MAX_REASSEMBLY_LEN: usize = 2 * MAX_MESSAGE_LEN;
+/// a message is dropped once none of its fragments have arrived for this long
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const FRAGMENT_HEADER_LEN: usize = 17;
+
+/// packet type of the whole message u8 | message id u64 | offset u32 | message length u32
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentHeader {
+    packet_type: PacketType,
+    id: u64,
+    offset: u32,
+    total: u32,
+}
+impl FragmentHeader {
+    pub fn new(packet_type: PacketType, id: u64, offset: u32, total: u32) -> Self {
+        Self {
+            packet_type,
+            id,
+            offset,
+            total,
+        }
+    }
+    pub fn packet_type(&self) -> PacketType {
+        self.packet_type
+    }
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut outvec = Vec::with_capacity(FRAGMENT_HEADER_LEN);
+        outvec.push(self.packet_type.to_u8().unwrap_or_default());
+        outvec.extend_from_slice(&self.id.to_be_bytes());
+        outvec.extend_from_slice(&self.offset.to_be_bytes());
+        outvec.extend_from_slice(&self.total.to_be_bytes());
+        outvec
+    }
+    pub fn from_raw(data: &[u8]) -> Result<Self, NetworkError> {
+        if data.len() < FRAGMENT_HEADER_LEN {
+            return Err(NetworkError::ConnectionDenied(
+                "fragment header too short".to_string(),
+            ));
+        }
+        let packet_type = FromPrimitive::from_u8(data[0]).unwrap_or_default();
+        let id = u64::from_be_bytes(data[1..9].try_into()?);
+        let offset = u32::from_be_bytes(data[9..13].try_into()?);
+        let total = u32::from_be_bytes(data[13..17].try_into()?);
+        Ok(Self::new(packet_type, id, offset, total))
+    }
+}
+
+/// number of datagrams seal_fragments makes of a message len bytes long
+pub fn packet_count(header: &StreamHeader, len: usize, mtu: usize) -> usize {
+    let overhead = seal_overhead(header);
+    if len + overhead <= mtu {
+        return 1;
+    }
+    len.div_ceil(chunk_len(overhead, mtu))
+}
+fn chunk_len(overhead: usize, mtu: usize) -> usize {
+    mtu.saturating_sub(overhead + FRAGMENT_HEADER_LEN).max(1)
+}
+
+/// encrypts a message into as many datagrams as it takes to keep each within mtu,
+/// a message that fits is sent as a single packet of the given type
+pub fn seal_fragments(
+    header: &StreamHeader,
+    seq: &SeqCounter,
+    remote_id: ConnectionId,
+    packet_type: PacketType,
+    inbuf: &[u8],
+    mtu: usize,
+) -> Vec<Vec<u8>> {
+    let overhead = seal_overhead(header);
+    if inbuf.len() + overhead <= mtu {
+        return vec![seal(header, seq, remote_id, packet_type, inbuf)];
+    }
+    // the id is a sequence number of its own, so it is unique for the life of the stream
+    let id = seq.next();
+    let chunk_len = chunk_len(overhead, mtu);
+    inbuf
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let fragment =
+                FragmentHeader::new(packet_type, id, (i * chunk_len) as u32, inbuf.len() as u32);
+            let mut payload = fragment.to_raw();
+            payload.extend_from_slice(chunk);
+            seal(header, seq, remote_id, PacketType::Fragment, &payload)
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct Partial {
+    packet_type: PacketType,
+    total: usize,
+    // what arrived so far by where it starts, the ranges never overlap
+    chunks: BTreeMap<usize, Vec<u8>>,
+    received: usize,
+    // when the last fragment arrived, a big message can take a while to send at a slow pace
+    last_heard: Instant,
+}
+impl Partial {
+    fn new(packet_type: PacketType, total: usize, now: Instant) -> Self {
+        Self {
+            packet_type,
+            total,
+            chunks: BTreeMap::new(),
+            received: 0,
+            last_heard: now,
+        }
+    }
+    // the parts of offset..end that haven't arrived yet
+    fn missing(&self, offset: usize, end: usize) -> Vec<Range<usize>> {
+        // the chunk starting before offset may reach into it
+        let from = self
+            .chunks
+            .range(..=offset)
+            .next_back()
+            .map_or(offset, |(start, _)| *start);
+        let mut missing = Vec::new();
+        let mut next = offset;
+        for (start, chunk) in self.chunks.range(from..end) {
+            if *start > next {
+                missing.push(next..*start);
+            }
+            next = next.max(start + chunk.len());
+        }
+        if next < end {
+            missing.push(next..end);
+        }
+        missing
+    }
+    // only once every byte is there, the chunks are the whole message in order
+    fn assemble(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.total);
+        for chunk in self.chunks.into_values() {
+            data.extend(chunk);
+        }
+        data
+    }
+}
+
+/// collects the fragments of a stream until their messages are complete
+#[derive(Debug)]
+pub struct Reassembler {
+    partial: HashMap<u64, Partial>,
+    // bytes received of the messages in partial
+    buffered: usize,
+    timeout: Duration,
+    max_len: usize,
+    // no message expires before then
+    next_expiry: Option<Instant>,
+}
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT, MAX_REASSEMBLY_LEN)
+    }
+}
+impl Reassembler {
+    pub fn new(timeout: Duration, max_len: usize) -> Self {
+        Self {
+            partial: HashMap::new(),
+            buffered: 0,
+            timeout,
+            max_len,
+            next_expiry: None,
+        }
+    }
+    /// takes the decrypted payload of a Fragment packet, returns the message once all of it is there.
+    /// dropped messages are counted in stats
+    pub fn add(&mut self, payload: &[u8], stats: &StreamStats) -> Option<(PacketType, Vec<u8>)> {
+        let now = Instant::now();
+        let fragment = FragmentHeader::from_raw(payload).ok()?;
+        let chunk = &payload[FRAGMENT_HEADER_LEN..];
+        let total = fragment.total() as usize;
+        let offset = fragment.offset() as usize;
+        if chunk.is_empty() || offset + chunk.len() > total {
+            return None;
+        }
+        if total > self.max_len {
+            stats.add_incomplete();
+            return None;
+        }
+        let partial = self
+            .partial
+            .entry(fragment.id())
+            .or_insert_with(|| Partial::new(fragment.packet_type(), total, now));
+        if partial.total != total {
+            return None;
+        }
+        partial.last_heard = now;
+        // only what hasn't arrived yet is kept, so a fragment overlapping others can't fill a gap twice
+        for range in partial.missing(offset, offset + chunk.len()) {
+            let len = range.len();
+            let bytes = chunk[range.start - offset..range.end - offset].to_vec();
+            partial.chunks.insert(range.start, bytes);
+            partial.received += len;
+            self.buffered += len;
+        }
+        if partial.received == total {
+            let partial = self.partial.remove(&fragment.id())?;
+            self.buffered -= partial.received;
+            return Some((partial.packet_type, partial.assemble()));
+        }
+        self.next_expiry.get_or_insert(now + self.timeout);
+        // what was received counts rather than what a message claims to be, so the claim costs nothing
+        while self.buffered > self.max_len {
+            self.drop_oldest(stats);
+        }
+        None
+    }
+    /// when expire next has something to drop, None while no message is partly received
+    pub fn deadline(&self) -> Option<Instant> {
+        self.next_expiry
+    }
+    /// drops the messages none of whose fragments have arrived for the timeout, the session calls it on its timer
+    pub fn expire(&mut self, now: Instant, stats: &StreamStats) {
+        match self.next_expiry {
+            Some(expiry) if expiry <= now => (),
+            _ => return,
+        }
+        let timeout = self.timeout;
+        let expired: Vec<u64> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| partial.last_heard + timeout <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(id, stats);
+        }
+        self.next_expiry = self
+            .partial
+            .values()
+            .map(|partial| partial.last_heard + timeout)
+            .min();
+    }
+    fn drop_oldest(&mut self, stats: &StreamStats) {
+        let oldest = self
+            .partial
+            .iter()
+            .min_by_key(|(_, partial)| partial.last_heard)
+            .map(|(id, _)| *id);
+        if let Some(id) = oldest {
+            self.remove(id, stats);
+        }
+    }
+    fn remove(&mut self, id: u64, stats: &StreamStats) {
+        if let Some(partial) = self.partial.remove(&id) {
+            self.buffered -= partial.received;
+            stats.add_incomplete();
+        }
+    }
+}
+
+#[test]
+fn fragment_header_raw_test() {
+    let header = FragmentHeader::new(PacketType::RawDataAck, 7, 1300, 65536);
+    let raw = header.to_raw();
+    assert_eq!(raw.len(), FRAGMENT_HEADER_LEN);
+    assert_eq!(FragmentHeader::from_raw(&raw).unwrap(), header);
+    assert!(FragmentHeader::from_raw(&raw[1..]).is_err());
+}
+#[test]
+fn reassembly_test() {
+    use crate::encryption::sym_aes_decrypt;
+    let header = StreamHeader::new(0);
+    let seq = SeqCounter::default();
+    let stats = StreamStats::default();
+    let message: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+    let id = ConnectionId::new(7);
+    let mut packets = seal_fragments(&header, &seq, id, PacketType::RawData, &message, 1000);
+    assert!(packets.len() > 5);
+    assert_eq!(packets.len(), packet_count(&header, message.len(), 1000));
+    assert!(packets.iter().all(|packet| packet.len() <= 1000));
+    // any order will do
+    packets.reverse();
+    let mut reassembler = Reassembler::default();
+    let mut whole = None;
+    for packet in packets.iter_mut() {
+        assert_eq!(ConnectionId::from_datagram(packet), Some(id));
+        let (cid, sealed) = packet.split_at_mut(crate::protocol::CID_LEN);
+        let (payload, remote_header, _) = sym_aes_decrypt(&header, cid, sealed).unwrap();
+        assert_eq!(remote_header.packet_type(), PacketType::Fragment);
+        assert!(whole.is_none());
+        whole = reassembler.add(&payload, &stats);
+    }
+    assert_eq!(whole, Some((PacketType::RawData, message.clone())));
+    assert_eq!(reassembler.buffered, 0);
+
+    // small messages aren't fragmented
+    let packets = seal_fragments(&header, &seq, id, PacketType::RawData, b"small", 1000);
+    assert_eq!(packets.len(), 1);
+
+    // a message that never completes is dropped after the timeout, or to make room for a newer one.
+    // only what arrived counts against the limit, not the length the fragments claim
+    let fragment = |id: u64| {
+        let mut payload = FragmentHeader::new(PacketType::RawData, id, 0, 240).to_raw();
+        payload.extend_from_slice(&[0; 100]);
+        payload
+    };
+    let mut reassembler = Reassembler::new(Duration::from_millis(50), 250);
+    assert!(reassembler.add(&fragment(1), &stats).is_none());
+    assert!(reassembler.add(&fragment(2), &stats).is_none());
+    assert_eq!(reassembler.buffered, 200);
+    assert_eq!(stats.incomplete(), 0);
+    assert!(reassembler.add(&fragment(3), &stats).is_none());
+    assert_eq!(stats.incomplete(), 1);
+    assert_eq!(reassembler.buffered, 200);
+    let deadline = reassembler.deadline().unwrap();
+    reassembler.expire(deadline - Duration::from_millis(1), &stats);
+    assert_eq!(stats.incomplete(), 1);
+    std::thread::sleep(Duration::from_millis(60));
+    reassembler.expire(Instant::now(), &stats);
+    assert_eq!(stats.incomplete(), 3);
+    assert_eq!(reassembler.buffered, 0);
+    assert_eq!(reassembler.deadline(), None);
+}
+#[test]
+fn overlapping_fragments_test() {
+    let stats = StreamStats::default();
+    let message: Vec<u8> = (0..10u8).collect();
+    let fragment = |offset: usize, end: usize| {
+        let mut payload = FragmentHeader::new(PacketType::RawData, 1, offset as u32, 10).to_raw();
+        payload.extend_from_slice(&message[offset..end]);
+        payload
+    };
+    let mut reassembler = Reassembler::default();
+    // twelve bytes of the ten, but 8 and 9 never came
+    assert!(reassembler.add(&fragment(0, 6), &stats).is_none());
+    assert!(reassembler.add(&fragment(2, 8), &stats).is_none());
+    assert!(reassembler.add(&fragment(2, 8), &stats).is_none());
+    assert_eq!(reassembler.buffered, 8);
+    assert!(reassembler.add(&fragment(7, 9), &stats).is_none());
+    assert_eq!(
+        reassembler.add(&fragment(5, 10), &stats),
+        Some((PacketType::RawData, message.clone()))
+    );
+    assert_eq!(reassembler.buffered, 0);
+}
@@ -1,55 +1,98 @@
 #[macro_use]
 extern crate serde_derive;
+mod adapter;
+mod channel;
+mod congestion;
 mod encryption;
+mod fragment;
+mod liveness;
+mod pmtu;
 pub mod netcore;
 mod protocol;
+mod ratelimit;
+mod rekey;
+mod reliable;
+mod session;
 pub use netcore::*;
+pub use adapter::{Delivery, PollRecv, PollSend, SllpDatagrams, SllpIo, MAX_WRITE_LEN};
+pub use channel::{ChannelInfo, SllpChannel, STREAM_PRIORITY};
+pub use congestion::{Bbr, CongestionAlgorithm, CongestionControl, NewReno};
+pub use encryption::{BigNum, PrivKeyComp, PubKeyComp, SessionKey};
+pub use fragment::MAX_MESSAGE_LEN;
+pub use liveness::{LivenessState, DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE};
+pub use pmtu::{BASE_MTU, MAX_MTU};
+pub use protocol::{
+    CipherSuite, ConnectionId, DeliveryMode, HandshakeMode, Message, PacketType, RemotePeer,
+    StreamStats, DEFAULT_HANDSHAKE_PORT,
+};
+pub use ratelimit::{RateLimit, SendLimits, SharedLimiter};
+pub use rekey::{RekeyPolicy, KEY_OVERLAP, MAX_KEYS_AHEAD};
 pub mod utils;
 pub use utils::*;
-use crate::encryption::{PrivKeyComp, PubKeyComp};
 use std::error::Error;
 use std::net::IpAddr;
 use std::sync::mpsc::*;
 use std::thread;
 use std::time::Duration;
-pub use utils::*;
 // ===================================================================
 //                                 Dependencies
 // ===================================================================
 //use crate::asyncronous::{AsyncNetworkHost};
 use crate::encryption::{
-    asym_aes_decrypt as aes_decrypt, asym_aes_encrypt as aes_encrypt, sym_aes_decrypt,
-    sym_aes_encrypt,
+    sign_transcript, sym_aes_decrypt, sym_aes_encrypt, sym_overhead, transcript_hash,
+    verify_transcript, CookieJar, EphemeralKey,
 };
-use crate::{
-    protocol::RemotePeer, protocol::StreamHeader, random_string, AsyncQuery, L4Addr, NetworkError,
-    Query,
+use crate::channel::{AcceptedChannel, ChannelHandle};
+use crate::fragment::packet_count;
+use crate::ratelimit::{Admission, PeerLimiters};
+use crate::protocol::{
+    ClientHello, HandshakeMsg, SeqCounter, ServerHello, StreamHeader, CID_LEN, HANDSHAKE_MAGIC,
 };
 use async_trait::async_trait;
 use futures::{
     future::Future,
     task::{Context, Poll},
 };
-use rsa::{RSAPrivateKey, RSAPublicKey};
-use std::collections::HashMap;
+use rsa::RSAPrivateKey;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, UdpSocket},
+    net::{udp::SendHalf, TcpListener, TcpStream, UdpSocket},
     stream::Stream,
+    time::timeout_at,
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{channel, error::TrySendError, Receiver, Sender},
         Mutex, MutexGuard,
     },
 };
+#[cfg(test)]
+use tokio::time::timeout;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct ArtificeConfig {
     broadcast: bool,
     addr: L4Addr,
     host: ArtificeHostData,
+    /// used for outgoing streams, and the weakest suite accepted from incoming streams
+    #[serde(default)]
+    cipher_suite: CipherSuite,
+    /// tcp address incoming handshakes are accepted on, None means DEFAULT_HANDSHAKE_PORT on the ip of addr
+    #[serde(default)]
+    handshake_addr: Option<L4Addr>,
+    #[serde(default)]
+    handshake_mode: HandshakeMode,
+    #[serde(default)]
+    stream: StreamConfig,
+    /// on everything the streams of the socket send together
+    #[serde(default)]
+    send_limit: RateLimit,
+    /// on what the socket accepts from each address, datagrams over it are dropped
+    #[serde(default)]
+    recv_limit: RateLimit,
 }
 impl ArtificeConfig {
     pub fn new(addr: L4Addr, host: ArtificeHostData, broadcast: bool) -> Self {
@@ -57,6 +100,12 @@ impl ArtificeConfig {
             broadcast,
             addr,
             host,
+            cipher_suite: CipherSuite::default(),
+            handshake_addr: None,
+            handshake_mode: HandshakeMode::default(),
+            stream: StreamConfig::default(),
+            send_limit: RateLimit::unlimited(),
+            recv_limit: RateLimit::unlimited(),
         }
     }
     /// used to create new host, primarily designed for use by the installer crate
@@ -67,6 +116,12 @@ impl ArtificeConfig {
             broadcast,
             addr,
             host,
+            cipher_suite: CipherSuite::default(),
+            handshake_addr: None,
+            handshake_mode: HandshakeMode::default(),
+            stream: StreamConfig::default(),
+            send_limit: RateLimit::unlimited(),
+            recv_limit: RateLimit::unlimited(),
         }
     }
     pub fn host_data(&self) -> &ArtificeHostData {
@@ -87,6 +142,138 @@ impl ArtificeConfig {
     pub fn set_socket_addr(&mut self, addr: SocketAddr) {
         self.addr = addr.into();
     }
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
+    }
+    /// streams opened by this host use this suite, and peers offering a weaker one are refused
+    pub fn set_cipher_suite(&mut self, cipher_suite: CipherSuite) {
+        self.cipher_suite = cipher_suite;
+    }
+    pub fn handshake_addr(&self) -> L4Addr {
+        match self.handshake_addr {
+            Some(addr) => addr,
+            None => L4Addr::new(self.addr.ip(), DEFAULT_HANDSHAKE_PORT),
+        }
+    }
+    /// port 0 lets the os pick a free port, see SllpSocket::handshake_addr for the one chosen
+    pub fn set_handshake_addr(&mut self, addr: SocketAddr) {
+        self.handshake_addr = Some(addr.into());
+    }
+    pub fn handshake_mode(&self) -> HandshakeMode {
+        self.handshake_mode
+    }
+    /// both ends of a stream must use the same mode, in udp mode the handshake address isn't used
+    pub fn set_handshake_mode(&mut self, handshake_mode: HandshakeMode) {
+        self.handshake_mode = handshake_mode;
+    }
+    pub fn stream_config(&self) -> &StreamConfig {
+        &self.stream
+    }
+    /// applies to streams opened and accepted after the socket is created from this config
+    pub fn set_stream_config(&mut self, stream: StreamConfig) {
+        self.stream = stream;
+    }
+    pub fn send_limit(&self) -> RateLimit {
+        self.send_limit
+    }
+    /// shared by every stream of the socket, a send over it fails with NetworkError::RateLimited
+    pub fn set_send_limit(&mut self, send_limit: RateLimit) {
+        self.send_limit = send_limit;
+    }
+    pub fn recv_limit(&self) -> RateLimit {
+        self.recv_limit
+    }
+    /// applies to each source address on its own, so one noisy peer can't crowd out the rest
+    pub fn set_recv_limit(&mut self, recv_limit: RateLimit) {
+        self.recv_limit = recv_limit;
+    }
+}
+/// settings for the streams of a socket, each end of a stream uses its own
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamConfig {
+    congestion_control: CongestionAlgorithm,
+    send_limit: RateLimit,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    rekey: RekeyPolicy,
+}
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            congestion_control: CongestionAlgorithm::default(),
+            send_limit: RateLimit::unlimited(),
+            keepalive: Some(DEFAULT_KEEPALIVE),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            rekey: RekeyPolicy::default(),
+        }
+    }
+}
+impl StreamConfig {
+    pub fn congestion_control(&self) -> CongestionAlgorithm {
+        self.congestion_control
+    }
+    /// only reliable streams get the acknowledgements congestion control needs, other modes send as fast as they are asked to
+    pub fn set_congestion_control(&mut self, congestion_control: CongestionAlgorithm) {
+        self.congestion_control = congestion_control;
+    }
+    pub fn send_limit(&self) -> RateLimit {
+        self.send_limit
+    }
+    /// on each stream by itself, a send over it fails with NetworkError::RateLimited
+    pub fn set_send_limit(&mut self, send_limit: RateLimit) {
+        self.send_limit = send_limit;
+    }
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+    /// a stream that hears nothing from its peer for this long pings it, None never does.
+    /// keep it well under the idle timeout, so a lost ping or two doesn't end the stream
+    pub fn set_keepalive(&mut self, keepalive: Option<Duration>) {
+        self.keepalive = keepalive;
+    }
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+    /// a stream that hears nothing from its peer for this long is closed, and fails with a timeout
+    /// from then on, None keeps it open however long the peer is gone
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+    pub fn rekey(&self) -> RekeyPolicy {
+        self.rekey
+    }
+    /// when the stream moves on to a new key for what it sends, the peer follows whatever its own policy is
+    pub fn set_rekey(&mut self, rekey: RekeyPolicy) {
+        self.rekey = rekey;
+    }
+}
+/// what the streams of a socket share with it
+#[derive(Debug, Clone, Default)]
+pub struct StreamContext {
+    config: StreamConfig,
+    send_limit: SharedLimiter,
+    streams: Streams,
+}
+impl StreamContext {
+    pub fn new(config: StreamConfig, send_limit: SharedLimiter, streams: Streams) -> Self {
+        Self {
+            config,
+            send_limit,
+            streams,
+        }
+    }
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+    /// the limit of the socket, on what all of its streams send together
+    pub fn send_limit(&self) -> &SharedLimiter {
+        &self.send_limit
+    }
+    /// where the socket finds the stream for an address, a stream takes itself out once closed
+    pub fn streams(&self) -> &Streams {
+        &self.streams
+    }
 }
 
 /// provides a means of saving private keys to files, because the process of generating the keys takes a really long time, but creating them from existing values does not
@@ -213,49 +400,269 @@ pub trait ConnectionRequest {
     unsafe fn unverify(self) -> Self::NetStream;
 }
 
+// signature and key derivation labels, so a value computed for one purpose is never valid for another
+const SERVER_LABEL: &[u8] = b"sllp server signature";
+const CLIENT_LABEL: &[u8] = b"sllp client signature";
+const KEY_LABEL: &[u8] = b"sllp session key";
+/// how long a handshake may take before it is abandoned, on either end
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// number of datagrams buffered for each stream before new ones are dropped
+const STREAM_CHANNEL_LEN: usize = 200;
+/// datagrams waiting to be sent on one connection, before the socket stops taking more until it catches up
+const MAX_CONNECTION_QUEUE: usize = 256;
+/// bytes of datagrams the os holds for the socket until they are read
+#[cfg(target_os = "linux")]
+const RECV_BUFFER_LEN: libc::c_int = 4 * 1024 * 1024;
+/// largest handshake message that will be accepted
+const MAX_FRAME_LEN: usize = 65535;
+/// how long a udp handshake waits for an answer before resending, doubled after every attempt
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_RETRANSMIT_INTERVAL: Duration = Duration::from_secs(2);
+/// udp handshakes past the cookie check that the server keeps state for at once
+const MAX_PENDING_HANDSHAKES: usize = 256;
+
+// handshake messages are length prefixed, so they can't be split or merged by tcp
+async fn write_frame(stream: &mut TcpStream, data: &[u8]) -> Result<(), NetworkError> {
+    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(data).await?;
+    Ok(())
+}
+async fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>, NetworkError> {
+    let mut len_buf = [0u8; 4];
+    if let Err(e) = stream.read_exact(&mut len_buf).await {
+        return Err(match e.kind() {
+            // the peer closes the connection when it refuses the handshake, such as for a weak cipher suite
+            std::io::ErrorKind::UnexpectedEof => {
+                NetworkError::ConnectionDenied("peer closed the connection".to_string())
+            }
+            _ => e.into(),
+        });
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(NetworkError::ConnectionDenied(
+            "handshake message too long".to_string(),
+        ));
+    }
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data).await?;
+    Ok(data)
+}
+
 // used to create handshake between both sides of sllp stream
+// the session key comes from an ephemeral x25519 exchange, the rsa keys only sign the transcript
+// the server must prove it holds the private key for peer.pubkey(), and the client for the key in its hello
 async fn handshake(
-    header: &StreamHeader,
     peer: &RemotePeer,
     priv_key: &RSAPrivateKey,
-    sender_addr: SocketAddr,
-) -> Result<(), NetworkError> {
-    let addr: SocketAddr = peer.socket_addr();
-    let mut tcpstream = TcpStream::connect(addr).await?;
-    let sender_addr: L4Addr = sender_addr.into();
-    tcpstream
-        .write(&aes_encrypt(
-            &RSAPublicKey::from(priv_key),
-            header.clone(),
-            &serde_json::to_string(&peer)?.into_bytes(),
-        )?)
-        .await?;
-    let mut inbuf: [u8; 1000] = [0; 1000];
-    let data_len = tcpstream.read(&mut inbuf).await?;
-    let (dec_data, new_header) = aes_decrypt(&priv_key, &mut inbuf[0..data_len])?;
-    if header.checksum() != new_header.checksum() {
+    ephemeral: EphemeralKey,
+    hello: &ClientHello,
+) -> Result<(StreamHeader, ConnectionId), NetworkError> {
+    let mut tcpstream = TcpStream::connect(peer.handshake_addr()).await?;
+    let cipher_suite = hello.cipher_suite();
+    let client_hello = serde_json::to_vec(hello)?;
+    write_frame(&mut tcpstream, &client_hello).await?;
+
+    let server_hello_data = read_frame(&mut tcpstream).await?;
+    let server_signature = read_frame(&mut tcpstream).await?;
+    let server_hello =
+        verify_server_hello(peer, &client_hello, &server_hello_data, &server_signature)?;
+    let client_signature = sign_transcript(
+        priv_key,
+        &transcript_hash(CLIENT_LABEL, &[&client_hello, &server_hello_data]),
+    )?;
+    write_frame(&mut tcpstream, &client_signature).await?;
+
+    let key = ephemeral.derive_session_key(
+        server_hello.ephemeral_key(),
+        &transcript_hash(KEY_LABEL, &[&client_hello, &server_hello_data]),
+        cipher_suite,
+    );
+    let header = StreamHeader::with_key(key, 0);
+    // the server confirms it derived the same key
+    check_confirmation(&header, read_frame(&mut tcpstream).await?)?;
+    Ok((header, server_hello.connection_id()))
+}
+// anyone can send a valid signature for their own key, so it has to be the key we expected
+fn verify_server_hello(
+    peer: &RemotePeer,
+    client_hello: &[u8],
+    server_hello_data: &[u8],
+    server_signature: &[u8],
+) -> Result<ServerHello, NetworkError> {
+    let server_hello: ServerHello = serde_json::from_slice(server_hello_data)?;
+    if server_hello.pubkey() != peer.pubkey() {
         return Err(NetworkError::ConnectionDenied(
-            "headers don't match".to_string(),
+            "server public key doesn't match the expected peer".to_string(),
         ));
     }
-    //let in_data = String::from_utf8(dec_data)?;
-    
-    tcpstream.write(&aes_encrypt(&RSAPublicKey::from(priv_key), header.clone(), &dec_data)?).await?;
-    let dec_res_len = tcpstream.read(&mut inbuf).await?;
-    let (dec_result, _, _) = sym_aes_decrypt(&header, &mut inbuf[0..dec_res_len])?;
-    let in_data = String::from_utf8(dec_result)?;
-    if in_data != "okay" {
+    verify_transcript(
+        &peer.pubkey().to_public_key()?,
+        &transcript_hash(SERVER_LABEL, &[client_hello, server_hello_data]),
+        server_signature,
+    )?;
+    Ok(server_hello)
+}
+fn check_confirmation(header: &StreamHeader, mut confirmation: Vec<u8>) -> Result<(), NetworkError> {
+    let (dec_result, _, _) = sym_aes_decrypt(header, &[], &mut confirmation)?;
+    if dec_result != b"okay" {
         return Err(NetworkError::ConnectionDenied(String::from(
             "connection failed",
         )));
     }
     Ok(())
 }
+// makes the handshake for a new stream in whichever mode the socket is configured for,
+// local_id is the connection id this end receives on, the one the peer picked is returned with the header
+#[allow(clippy::too_many_arguments)]
+async fn open_session(
+    peer: &RemotePeer,
+    priv_key: &RSAPrivateKey,
+    sender_addr: SocketAddr,
+    cipher_suite: CipherSuite,
+    delivery_mode: DeliveryMode,
+    handshake_mode: HandshakeMode,
+    handshakes: &Handshakes,
+    outgoing_sender: &Sender<OutgoingMsg>,
+    local_id: ConnectionId,
+) -> Result<(StreamHeader, ConnectionId), NetworkError> {
+    let ephemeral = EphemeralKey::generate();
+    let hello = ClientHello::new(
+        RemotePeer::new(sender_addr.into(), PubKeyComp::from(priv_key)),
+        cipher_suite,
+        ephemeral.public_bytes(),
+        delivery_mode,
+        local_id,
+    );
+    let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+    match handshake_mode {
+        // a server that takes the connection and never answers mustn't hang connect
+        HandshakeMode::Tcp => within(deadline, handshake(peer, priv_key, ephemeral, &hello)).await,
+        HandshakeMode::Udp => {
+            // replies are matched to the handshake by the connection id it carries,
+            // so any number of them can run with the same peer
+            let key = (peer.socket_addr(), local_id);
+            let (reply_sender, mut replies) = channel(STREAM_CHANNEL_LEN);
+            handshakes.lock().await.insert(key, reply_sender);
+            let result = within(
+                deadline,
+                udp_handshake(
+                    peer,
+                    priv_key,
+                    ephemeral,
+                    &hello,
+                    outgoing_sender.clone(),
+                    &mut replies,
+                ),
+            )
+            .await;
+            handshakes.lock().await.remove(&key);
+            result
+        }
+    }
+}
+// the same exchange as handshake, but over the udp socket, each message is resent until answered
+// and the server may first answer with a cookie, proving the client can receive at its address
+async fn udp_handshake(
+    peer: &RemotePeer,
+    priv_key: &RSAPrivateKey,
+    ephemeral: EphemeralKey,
+    hello: &ClientHello,
+    mut outgoing_sender: Sender<OutgoingMsg>,
+    replies: &mut Receiver<Datagram>,
+) -> Result<(StreamHeader, ConnectionId), NetworkError> {
+    let remote_addr = peer.socket_addr();
+    let id = hello.connection_id();
+    let cipher_suite = hello.cipher_suite();
+    let client_hello = serde_json::to_vec(hello)?;
+    let mut request = HandshakeMsg::ClientHello {
+        cookie: Vec::new(),
+        hello: client_hello.clone(),
+    };
+    let (server_hello_data, server_signature) = loop {
+        match udp_request(&mut outgoing_sender, (remote_addr, id), &request, replies, |reply| {
+            matches!(
+                reply,
+                HandshakeMsg::Retry { .. } | HandshakeMsg::ServerHello { .. }
+            )
+        })
+        .await?
+        {
+            HandshakeMsg::Retry { cookie } => {
+                request = HandshakeMsg::ClientHello {
+                    cookie,
+                    hello: client_hello.clone(),
+                }
+            }
+            HandshakeMsg::ServerHello { hello, signature } => break (hello, signature),
+            _ => unreachable!(),
+        }
+    };
+    let server_hello =
+        verify_server_hello(peer, &client_hello, &server_hello_data, &server_signature)?;
+    let client_signature = sign_transcript(
+        priv_key,
+        &transcript_hash(CLIENT_LABEL, &[&client_hello, &server_hello_data]),
+    )?;
+    let key = ephemeral.derive_session_key(
+        server_hello.ephemeral_key(),
+        &transcript_hash(KEY_LABEL, &[&client_hello, &server_hello_data]),
+        cipher_suite,
+    );
+    let header = StreamHeader::with_key(key, 0);
+    let finish = HandshakeMsg::Finish {
+        signature: client_signature,
+    };
+    match udp_request(&mut outgoing_sender, (remote_addr, id), &finish, replies, |reply| {
+        matches!(reply, HandshakeMsg::Confirm { .. })
+    })
+    .await?
+    {
+        HandshakeMsg::Confirm { data } => check_confirmation(&header, data)?,
+        _ => unreachable!(),
+    }
+    Ok((header, server_hello.connection_id()))
+}
+// send a handshake message until a reply accepted by expected arrives,
+// anything else, such as a duplicate of an earlier reply, is ignored
+async fn udp_request(
+    outgoing_sender: &mut Sender<OutgoingMsg>,
+    (remote_addr, id): (SocketAddr, ConnectionId),
+    request: &HandshakeMsg,
+    replies: &mut Receiver<Datagram>,
+    expected: fn(&HandshakeMsg) -> bool,
+) -> Result<HandshakeMsg, NetworkError> {
+    let raw = request.to_raw(id);
+    let mut interval = RETRANSMIT_INTERVAL;
+    loop {
+        outgoing_sender.send((raw.clone(), remote_addr)).await?;
+        let deadline = tokio::time::Instant::now() + interval;
+        loop {
+            let reply = match tokio::time::timeout_at(deadline, replies.recv()).await {
+                Ok(Some(reply)) => reply?,
+                Ok(None) => {
+                    return Err(NetworkError::IOError(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "channel closed",
+                    )))
+                }
+                Err(_) => break,
+            };
+            if let Ok((_, reply)) = HandshakeMsg::from_raw(&reply.0) {
+                if expected(&reply) {
+                    return Ok(reply);
+                }
+            }
+        }
+        interval = std::cmp::min(interval * 2, MAX_RETRANSMIT_INTERVAL);
+    }
+}
 fn incoming_conn(
     receiver: &mut Receiver<NewConnection>,
+    context: &StreamContext,
     ctx: &mut Context<'_>,
 ) -> Poll<Option<Result<AsyncRequest<SllpStream>, NetworkError>>> {
-    let (header, addr, query, pubkey) = match receiver.poll_recv(ctx) {
+    let (header, addr, (local_id, remote_id), query, pubkey, mode) = match receiver.poll_recv(ctx) {
         Poll::Ready(data) => match data {
             Some(data) => data?,
             None => return Poll::Ready(None),
@@ -264,51 +671,317 @@ fn incoming_conn(
     };
 
     Poll::Ready(Some(Ok(AsyncRequest::new(
-        match SllpStream::new(query, header, addr) {
-            Ok(stream) => stream,
-            Err(e) => return Poll::Ready(Some(Err(e))),
-        },
+        SllpStream::with_mode(query, header, addr, local_id, remote_id, false, mode, context),
         pubkey,
     ))))
 }
+// the server side of a handshake, between answering the client hello and checking the client signature
+struct ServerHandshake {
+    client_hello_data: Vec<u8>,
+    client_hello: ClientHello,
+    ephemeral: EphemeralKey,
+    server_hello_data: Vec<u8>,
+    server_signature: Vec<u8>,
+    local_id: ConnectionId,
+}
+impl ServerHandshake {
+    // local_id is the connection id the server receives the stream on
+    fn new(
+        (client_hello_data, client_hello): (Vec<u8>, ClientHello),
+        priv_key: &RSAPrivateKey,
+        min_suite: CipherSuite,
+        local_id: ConnectionId,
+    ) -> Result<Self, NetworkError> {
+        // the client picks the cipher suite, it is only accepted if at least as strong as ours
+        let cipher_suite = client_hello.cipher_suite();
+        if cipher_suite < min_suite {
+            return Err(NetworkError::ConnectionDenied(format!(
+                "cipher suite {:?} is weaker than the required {:?}",
+                cipher_suite, min_suite
+            )));
+        }
+        let ephemeral = EphemeralKey::generate();
+        let server_hello_data = serde_json::to_vec(&ServerHello::new(
+            PubKeyComp::from(priv_key),
+            ephemeral.public_bytes(),
+            local_id,
+        ))?;
+        let server_signature = sign_transcript(
+            priv_key,
+            &transcript_hash(SERVER_LABEL, &[&client_hello_data, &server_hello_data]),
+        )?;
+        Ok(Self {
+            client_hello_data,
+            client_hello,
+            ephemeral,
+            server_hello_data,
+            server_signature,
+            local_id,
+        })
+    }
+    // the client proves it holds the private key matching the public key it sent
+    fn finish(self, client_signature: &[u8]) -> Result<(StreamHeader, PubKeyComp), NetworkError> {
+        let pubkeycomp = self.client_hello.peer().pubkey().clone();
+        verify_transcript(
+            &pubkeycomp.to_public_key()?,
+            &transcript_hash(
+                CLIENT_LABEL,
+                &[&self.client_hello_data, &self.server_hello_data],
+            ),
+            client_signature,
+        )?;
+        let key = self.ephemeral.derive_session_key(
+            self.client_hello.ephemeral_key(),
+            &transcript_hash(
+                KEY_LABEL,
+                &[&self.client_hello_data, &self.server_hello_data],
+            ),
+            self.client_hello.cipher_suite(),
+        );
+        Ok((StreamHeader::with_key(key, 0), pubkeycomp))
+    }
+}
+// the whole handshake has to be done by deadline
 async fn recv_incoming(
-    listener: &mut TcpListener,
+    mut stream: TcpStream,
+    tcpaddr: SocketAddr,
     in_priv_key: &RSAPrivateKey,
     in_sender: &Streams,
     outgoing_sender: &Sender<OutgoingMsg>,
+    min_suite: CipherSuite,
 ) -> NewConnection {
-    let mut buffer: [u8; 65535] = [0; 65535];
-    let (mut stream, tcpaddr) = listener.accept().await?;
-    let data_len = stream.read(&mut buffer).await?;
-    let (dec_data, header) = aes_decrypt(&in_priv_key, &mut buffer[0..data_len])?;
-    let remote_peer: RemotePeer = serde_json::from_str(&String::from_utf8(dec_data)?)?;
-    let (layer3_addr, pubkeycomp) = remote_peer.decompose();
-    // then randomly generate a string encypt using public key
-    // then have client send the random string back, there by completing
-    // the challenge and verifyig the publickey
-    let addr = SocketAddr::new(tcpaddr.ip(), layer3_addr.port());
-    let challenge_str = random_string(32);
-    let challenge = challenge_str.as_bytes();
-    stream.write(&aes_encrypt(&pubkeycomp.clone().into(), header, challenge)?).await?;
-    //buffer.clear();
-    let newlen = stream.read(&mut buffer).await?;
-    let (newdec, header) = aes_decrypt(&in_priv_key, &mut buffer[0..newlen])?;
-    if newdec != challenge {
-        // should really return an error here
-        panic!("public key verification failed");
-    }
-    stream.write(&sym_aes_encrypt(&header, b"okay")).await?;
-    // SllpSocket -> SllpStream Vec<u8> = data recv, usize = data length
-    let (incoming_sender, incoming_receiver): (Sender<IncomingMsg>, Receiver<(Vec<u8>, usize)>) =
-        channel(1);
+    let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+    let client_hello_data = within(deadline, read_frame(&mut stream)).await?;
+    let client_hello: ClientHello = serde_json::from_slice(&client_hello_data)?;
+    let addr = SocketAddr::new(tcpaddr.ip(), client_hello.peer().socket_addr().port());
+    // registered before anything is sent, so nothing the client sends as soon as it is done is missed
+    let (local_id, foward) = register_stream(addr, in_sender, outgoing_sender).await;
+    let answered = within(deadline, async {
+        let hello = (client_hello_data, client_hello);
+        let state = ServerHandshake::new(hello, in_priv_key, min_suite, local_id)?;
+        write_frame(&mut stream, &state.server_hello_data).await?;
+        write_frame(&mut stream, &state.server_signature).await?;
+
+        let client_signature = read_frame(&mut stream).await?;
+        let mode = state.client_hello.delivery_mode();
+        let remote_id = state.client_hello.connection_id();
+        let (header, pubkeycomp) = state.finish(&client_signature)?;
+        write_frame(&mut stream, &sym_aes_encrypt(&header, &[], b"okay")).await?;
+        Ok((header, remote_id, pubkeycomp, mode))
+    })
+    .await;
+    match answered {
+        Ok((header, remote_id, pubkeycomp, mode)) => Ok((
+            header,
+            addr,
+            (local_id, remote_id),
+            foward,
+            pubkeycomp,
+            mode,
+        )),
+        Err(e) => {
+            in_sender.lock().await.remove(&local_id);
+            Err(e)
+        }
+    }
+}
+// runs a step of a handshake, failing with a timeout once deadline passes
+async fn within<T>(
+    deadline: tokio::time::Instant,
+    step: impl Future<Output = Result<T, NetworkError>>,
+) -> Result<T, NetworkError> {
+    match timeout_at(deadline, step).await {
+        Ok(result) => result,
+        Err(_) => Err(NetworkError::IOError(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "handshake timed out",
+        ))),
+    }
+}
+// creates the channels of a new stream, and routes datagrams for a new connection id to it
+async fn register_stream(
+    addr: SocketAddr,
+    in_sender: &Streams,
+    outgoing_sender: &Sender<OutgoingMsg>,
+) -> (ConnectionId, AsyncQuery<OutgoingMsg, Datagram>) {
+    // SllpSocket -> SllpStream Vec<u8> = datagram, SocketAddr = where it came from
+    let (incoming_sender, incoming_receiver): (Sender<Datagram>, Receiver<Datagram>) =
+        channel(STREAM_CHANNEL_LEN);
+    // store incoming sender
+    let id = in_sender.register(addr, incoming_sender).await;
     // moved into the stream and pocesses a reciever to get incoming data, and a sender = outgoing_sender
     // to send to the sending thread
-    let foward: AsyncQuery<(Vec<u8>, SocketAddr), (Vec<u8>, usize)> =
-        AsyncQuery::create(outgoing_sender.clone(), incoming_receiver);
-    // store incoming sender
-    println!("recv addr: {}", addr);
-    in_sender.lock().await.insert(addr, incoming_sender);
-    Ok((header, addr, foward, pubkeycomp))
+    (
+        id,
+        AsyncQuery::create(outgoing_sender.clone(), incoming_receiver),
+    )
+}
+// answers a client hello that came over udp, the stream is routed from here on
+// so no other handshake takes its connection id
+async fn start_udp_incoming(
+    client_hello_data: Vec<u8>,
+    addr: SocketAddr,
+    priv_key: &RSAPrivateKey,
+    in_sender: &Streams,
+    outgoing_sender: &Sender<OutgoingMsg>,
+    min_suite: CipherSuite,
+) -> Result<(ServerHandshake, AsyncQuery<OutgoingMsg, Datagram>), NetworkError> {
+    let client_hello: ClientHello = serde_json::from_slice(&client_hello_data)?;
+    let (local_id, foward) = register_stream(addr, in_sender, outgoing_sender).await;
+    let hello = (client_hello_data, client_hello);
+    match ServerHandshake::new(hello, priv_key, min_suite, local_id) {
+        Ok(state) => Ok((state, foward)),
+        Err(e) => {
+            in_sender.lock().await.remove(&local_id);
+            Err(e)
+        }
+    }
+}
+// udp handshakes in progress on the server, kept so repeated messages get the same answer
+enum PendingHandshake {
+    // waiting for the client signature, with the server hello to resend,
+    // and the channels of the stream its connection id is already routed to
+    Started(
+        Box<ServerHandshake>,
+        Vec<u8>,
+        AsyncQuery<OutgoingMsg, Datagram>,
+    ),
+    // the stream exists, the confirmation is kept in case it was lost
+    Done(Vec<u8>),
+}
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+// accepts udp handshakes, nothing is stored and no signature is made for a client until it
+// returns a cookie sent to its address, so spoofed client hellos cost next to nothing
+async fn udp_handshake_listener(
+    mut datagrams: Receiver<(Vec<u8>, SocketAddr)>,
+    priv_key: RSAPrivateKey,
+    in_sender: Streams,
+    mut outgoing_sender: Sender<OutgoingMsg>,
+    mut request_sender: Sender<NewConnection>,
+    min_suite: CipherSuite,
+) {
+    let cookies = CookieJar::new();
+    // by address and connection id of the client, a client may run several at once
+    let mut pending: HashMap<HandshakeKey, (Instant, PendingHandshake)> = HashMap::new();
+    while let Some((data, addr)) = datagrams.recv().await {
+        // handshakes that were never finished give up their connection id
+        let mut abandoned = Vec::new();
+        pending.retain(|_, (started, handshake)| {
+            let expired = started.elapsed() >= HANDSHAKE_TIMEOUT;
+            if let (true, PendingHandshake::Started(state, ..)) = (expired, &*handshake) {
+                abandoned.push(state.local_id);
+            }
+            !expired
+        });
+        if !abandoned.is_empty() {
+            let mut streams = in_sender.lock().await;
+            for id in abandoned {
+                streams.remove(&id);
+            }
+        }
+        let (id, message) = match HandshakeMsg::from_raw(&data) {
+            Ok(parsed) => parsed,
+            Err(_) => continue,
+        };
+        let key = (addr, id);
+        let (reply, result) = match message {
+            HandshakeMsg::ClientHello { cookie, hello } => {
+                match pending.get(&key) {
+                    // the server hello was lost, or is still on its way
+                    Some((_, PendingHandshake::Started(state, server_hello, _)))
+                        if state.client_hello_data == hello =>
+                    {
+                        (Some(server_hello.clone()), None)
+                    }
+                    _ if !cookies.check(&addr, &cookie, unix_time()) => {
+                        let retry = HandshakeMsg::Retry {
+                            cookie: cookies.issue(&addr, unix_time()),
+                        };
+                        (Some(retry.to_raw(id)), None)
+                    }
+                    // the client will try again once there is room
+                    _ if pending.len() >= MAX_PENDING_HANDSHAKES => (None, None),
+                    _ => match start_udp_incoming(
+                        hello,
+                        addr,
+                        &priv_key,
+                        &in_sender,
+                        &outgoing_sender,
+                        min_suite,
+                    )
+                    .await
+                    {
+                        Ok((state, foward)) => {
+                            let server_hello = HandshakeMsg::ServerHello {
+                                hello: state.server_hello_data.clone(),
+                                signature: state.server_signature.clone(),
+                            }
+                            .to_raw(id);
+                            pending.insert(
+                                key,
+                                (
+                                    Instant::now(),
+                                    PendingHandshake::Started(
+                                        Box::new(state),
+                                        server_hello.clone(),
+                                        foward,
+                                    ),
+                                ),
+                            );
+                            (Some(server_hello), None)
+                        }
+                        Err(e) => (None, Some(Err(e))),
+                    },
+                }
+            }
+            HandshakeMsg::Finish { signature } => match pending.remove(&key) {
+                Some((started, PendingHandshake::Started(state, _, foward))) => {
+                    let mode = state.client_hello.delivery_mode();
+                    let ids = (state.local_id, state.client_hello.connection_id());
+                    match state.finish(&signature) {
+                        Ok((header, pubkeycomp)) => {
+                            let confirm = HandshakeMsg::Confirm {
+                                data: sym_aes_encrypt(&header, &[], b"okay"),
+                            }
+                            .to_raw(id);
+                            pending.insert(key, (started, PendingHandshake::Done(confirm.clone())));
+                            (
+                                Some(confirm),
+                                Some(Ok((header, addr, ids, foward, pubkeycomp, mode))),
+                            )
+                        }
+                        Err(e) => {
+                            in_sender.lock().await.remove(&ids.0);
+                            (None, Some(Err(e)))
+                        }
+                    }
+                }
+                Some((started, PendingHandshake::Done(confirm))) => {
+                    pending.insert(key, (started, PendingHandshake::Done(confirm.clone())));
+                    (Some(confirm), None)
+                }
+                None => (None, None),
+            },
+            // messages only a server sends are ignored
+            _ => (None, None),
+        };
+        if let Some(reply) = reply {
+            if outgoing_sender.send((reply, addr)).await.is_err() {
+                break;
+            }
+        }
+        if let Some(result) = result {
+            if request_sender.send(result).await.is_err() {
+                // the socket was dropped, so no one is listening anymore
+                break;
+            }
+        }
+    }
 }
 
 impl<T: AsyncDataStream> ConnectionRequest for AsyncRequest<T> {
@@ -337,37 +1010,144 @@ impl<T: AsyncDataStream> ConnectionRequest for AsyncRequest<T> {
 //                          Split type for Sllp Stream
 // ==========================================================================
 
+// encrypts the next packet of a stream, behind the connection id the peer receives it on
+fn seal(
+    header: &StreamHeader,
+    seq: &SeqCounter,
+    remote_id: ConnectionId,
+    packet_type: PacketType,
+    inbuf: &[u8],
+) -> Vec<u8> {
+    let mut header = header.clone();
+    header.set_seq(seq.next());
+    header.set_packet_type(packet_type);
+    // the id is sent in the clear, but a packet moved to another one won't decrypt
+    let mut packet = remote_id.to_bytes().to_vec();
+    packet.extend(sym_aes_encrypt(&header, &packet, inbuf));
+    packet
+}
+// bytes a sealed packet has on top of what it carries
+fn seal_overhead(header: &StreamHeader) -> usize {
+    CID_LEN + sym_overhead(header)
+}
+// a stream whose peer went quiet fails with a timeout, on either end of a split
+fn check_alive(stats: &StreamStats) -> Result<(), NetworkError> {
+    match stats.liveness() {
+        LivenessState::Dead => Err(NetworkError::IOError(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "nothing heard from the peer within the idle timeout",
+        ))),
+        _ => Ok(()),
+    }
+}
+// whether a message len bytes long, sent in that many packets, may go to the session
+fn check_send(
+    len: usize,
+    limits: &SendLimits,
+    packets: usize,
+    stats: &StreamStats,
+) -> Result<(), NetworkError> {
+    check_alive(stats)?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(NetworkError::MessageTooLarge(len));
+    }
+    limits.check(len, packets)
+}
+// hands a message to the session of the stream, which encrypts it
+async fn send_packet(
+    sender: &mut Sender<OutgoingMsg>,
+    remote_addr: SocketAddr,
+    inbuf: &[u8],
+    limits: &SendLimits,
+    packets: usize,
+    stats: &StreamStats,
+) -> Result<usize, NetworkError> {
+    check_send(inbuf.len(), limits, packets, stats)?;
+    if let Err(e) = sender.send((inbuf.to_vec(), remote_addr)).await {
+        // the session may have given up on the peer since
+        check_alive(stats)?;
+        return Err(e.into());
+    }
+    Ok(inbuf.len())
+}
+// waits for the next message the session of the stream has decrypted and checked,
+// None means the stream was closed
+async fn recv_message(
+    receiver: &mut Receiver<IncomingMsg>,
+    stats: &StreamStats,
+) -> Result<Option<Message>, NetworkError> {
+    match receiver.recv().await {
+        Some(result) => Ok(Some(result?)),
+        // the stream was closed, by this end or the peer, or the peer is gone
+        None => {
+            check_alive(stats)?;
+            Ok(None)
+        }
+    }
+}
+// no lengths at all means the stream was closed
+async fn recv_packet(
+    receiver: &mut Receiver<IncomingMsg>,
+    outbuf: &mut Vec<u8>,
+    stats: &StreamStats,
+) -> Result<Vec<usize>, NetworkError> {
+    match recv_message(receiver, stats).await? {
+        Some(message) => {
+            outbuf.extend_from_slice(message.data());
+            Ok(vec![message.data().len()])
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// owned half of SllpReceiver
 #[derive(Debug)]
 pub struct OwnedSllpReceiver {
     header: StreamHeader,
     receiver: Receiver<IncomingMsg>,
+    stats: Arc<StreamStats>,
+    mode: DeliveryMode,
+    // kept for reform, the halves don't open channels themselves
+    channels: ChannelHandle,
 }
 impl OwnedSllpReceiver {
-    pub fn new(header: StreamHeader, receiver: Receiver<IncomingMsg>) -> Self {
-        Self { header, receiver }
+    /// receiver carries the plain messages of a session, see SllpStream::into_split.
+    /// stats are those the session keeps, they tell the receiver once it is gone, and mode is the one it delivers in
+    pub fn new(
+        header: StreamHeader,
+        receiver: Receiver<IncomingMsg>,
+        stats: Arc<StreamStats>,
+        mode: DeliveryMode,
+    ) -> Self {
+        Self {
+            header,
+            receiver,
+            stats,
+            mode,
+            channels: ChannelHandle::closed(),
+        }
+    }
+    /// the next message, with what its packet said about it, None once the stream is closed.
+    /// unlike recv, messages never run together
+    pub async fn recv_msg(&mut self) -> Result<Option<Message>, NetworkError> {
+        recv_message(&mut self.receiver, &self.stats).await
+    }
+    /// number of replayed packets that have been dropped
+    pub fn replays(&self) -> u64 {
+        self.stats.replays()
+    }
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
     }
 }
 #[async_trait]
 impl AsyncRecv for OwnedSllpReceiver {
     type RecvError = NetworkError;
     async fn recv(&mut self, outbuf: &mut Vec<u8>) -> Result<Vec<usize>, NetworkError> {
-        let (mut data, data_len) = match self.receiver.recv().await {
-            Some(result) => result,
-            None => {
-                return Err(NetworkError::IOError(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionReset,
-                    "channel closed",
-                )))
-            }
-        };
-        let (dec_data, header, indexes) = sym_aes_decrypt(&self.header, &mut data[0..data_len])?;
-        if header.checksum() != self.header.checksum() {
-            return Err(NetworkError::ConnectionDenied(
-                "potential man in the middle attack".to_string(),
-            ));
-        }
-        outbuf.extend_from_slice(&dec_data);
-        Ok(indexes)
+        recv_packet(&mut self.receiver, outbuf, &self.stats).await
     }
     fn header(&self) -> &StreamHeader {
         &self.header
@@ -379,34 +1159,38 @@ impl AsyncRecv for OwnedSllpReceiver {
 pub struct SllpReceiver<'a> {
     header: &'a StreamHeader,
     receiver: &'a mut Receiver<IncomingMsg>,
+    stats: &'a StreamStats,
 }
 
 impl<'a> SllpReceiver<'a> {
-    pub fn new(header: &'a StreamHeader, receiver: &'a mut Receiver<IncomingMsg>) -> Self {
-        Self { header, receiver }
+    pub fn new(
+        header: &'a StreamHeader,
+        receiver: &'a mut Receiver<IncomingMsg>,
+        stats: &'a StreamStats,
+    ) -> Self {
+        Self {
+            header,
+            receiver,
+            stats,
+        }
+    }
+    /// the next message, with what its packet said about it, None once the stream is closed
+    pub async fn recv_msg(&mut self) -> Result<Option<Message>, NetworkError> {
+        recv_message(self.receiver, self.stats).await
+    }
+    /// number of replayed packets that have been dropped
+    pub fn replays(&self) -> u64 {
+        self.stats.replays()
+    }
+    pub fn stats(&self) -> &StreamStats {
+        self.stats
     }
 }
 #[async_trait]
 impl<'a> AsyncRecv for SllpReceiver<'a> {
     type RecvError = NetworkError;
     async fn recv(&mut self, outbuf: &mut Vec<u8>) -> Result<Vec<usize>, NetworkError> {
-        let (mut data, data_len) = match self.receiver.recv().await {
-            Some(result) => result,
-            None => {
-                return Err(NetworkError::IOError(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionReset,
-                    "channel closed",
-                )))
-            }
-        };
-        let (dec_data, header, indexes) = sym_aes_decrypt(&self.header, &mut data[0..data_len])?;
-        if self.header.checksum() != header.checksum() {
-            return Err(NetworkError::ConnectionDenied(String::from(
-                "header's don't match",
-            )));
-        }
-        outbuf.extend_from_slice(&dec_data);
-        Ok(indexes)
+        recv_packet(self.receiver, outbuf, self.stats).await
     }
     fn header(&self) -> &StreamHeader {
         self.header
@@ -418,24 +1202,54 @@ pub struct OwnedSllpSender {
     header: StreamHeader,
     remote_addr: SocketAddr,
     sender: Sender<OutgoingMsg>,
+    stats: Arc<StreamStats>,
+    limits: SendLimits,
+    mode: DeliveryMode,
 }
 impl OwnedSllpSender {
-    pub fn new(header: StreamHeader, remote_addr: SocketAddr, sender: Sender<OutgoingMsg>) -> Self {
+    /// sender goes to the session that encrypts the messages, see SllpStream::into_split.
+    /// stats are those the session keeps, they tell the sender once it is gone, and mode is the one it sends in
+    pub fn new(
+        header: StreamHeader,
+        remote_addr: SocketAddr,
+        sender: Sender<OutgoingMsg>,
+        stats: Arc<StreamStats>,
+        mode: DeliveryMode,
+    ) -> Self {
         Self {
             header,
             remote_addr,
             sender,
+            stats,
+            limits: SendLimits::default(),
+            mode,
         }
     }
+    pub fn header(&self) -> &StreamHeader {
+        &self.header
+    }
+    /// largest datagram known to reach the peer, bigger messages are sent in fragments
+    pub fn mtu(&self) -> usize {
+        self.stats.mtu()
+    }
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
+    }
 }
 #[async_trait]
 impl AsyncSend for OwnedSllpSender {
     type SendError = NetworkError;
     async fn send(&mut self, inbuf: &[u8]) -> Result<usize, NetworkError> {
-        self.sender
-            .send((sym_aes_encrypt(&self.header, inbuf), self.remote_addr))
-            .await?;
-        Ok(inbuf.len())
+        let packets = packet_count(&self.header, inbuf.len(), self.stats.mtu());
+        send_packet(
+            &mut self.sender,
+            self.remote_addr,
+            inbuf,
+            &self.limits,
+            packets,
+            &self.stats,
+        )
+        .await
     }
     fn remote_addr(&self) -> &SocketAddr {
         &self.remote_addr
@@ -448,28 +1262,43 @@ pub struct SllpSender<'a> {
     header: &'a StreamHeader,
     remote_addr: SocketAddr,
     sender: &'a mut Sender<OutgoingMsg>,
+    stats: &'a StreamStats,
+    limits: &'a SendLimits,
 }
 impl<'a> SllpSender<'a> {
     pub fn new(
         header: &'a StreamHeader,
         remote_addr: SocketAddr,
         sender: &'a mut Sender<OutgoingMsg>,
+        stats: &'a StreamStats,
+        limits: &'a SendLimits,
     ) -> Self {
         Self {
             header,
             remote_addr,
             sender,
+            stats,
+            limits,
         }
     }
+    pub fn header(&self) -> &StreamHeader {
+        self.header
+    }
 }
 #[async_trait]
 impl<'a> AsyncSend for SllpSender<'a> {
     type SendError = NetworkError;
     async fn send(&mut self, inbuf: &[u8]) -> Result<usize, NetworkError> {
-        self.sender
-            .send((sym_aes_encrypt(&self.header, inbuf), self.remote_addr))
-            .await?;
-        Ok(inbuf.len())
+        let packets = packet_count(self.header, inbuf.len(), self.stats.mtu());
+        send_packet(
+            self.sender,
+            self.remote_addr,
+            inbuf,
+            self.limits,
+            packets,
+            self.stats,
+        )
+        .await
     }
     fn remote_addr(&self) -> &SocketAddr {
         &self.remote_addr
@@ -490,16 +1319,29 @@ pub struct SllpStream {
     header: StreamHeader,
     query: AsyncQuery<OutgoingMsg, IncomingMsg>,
     remote_addr: SocketAddr,
+    stats: Arc<StreamStats>,
+    mode: DeliveryMode,
+    limits: SendLimits,
+    channels: ChannelHandle,
 }
 #[async_trait]
 impl AsyncSend for SllpStream {
     type SendError = NetworkError;
     async fn send(&mut self, inbuf: &[u8]) -> Result<usize, NetworkError> {
-        self.query
-            .send((sym_aes_encrypt(&self.header, inbuf), self.remote_addr))
-            .await?;
-        Ok(inbuf.len())
+        let packets = packet_count(&self.header, inbuf.len(), self.stats.mtu());
+        let (sender, _) = self.query.split();
+        send_packet(
+            sender,
+            self.remote_addr,
+            inbuf,
+            &self.limits,
+            packets,
+            &self.stats,
+        )
+        .await
     }
+    /// the address the stream was opened with, if the peer moves the stream follows it
+    /// but this stays the same, see StreamStats::migrations
     fn remote_addr(&self) -> &SocketAddr {
         &self.remote_addr
     }
@@ -508,29 +1350,50 @@ impl AsyncSend for SllpStream {
 impl AsyncRecv for SllpStream {
     type RecvError = NetworkError;
     async fn recv(&mut self, outbuf: &mut Vec<u8>) -> Result<Vec<usize>, NetworkError> {
-        let (mut data, data_len) = match self.query.recv().await {
-            Some(result) => result,
-            None => {
-                return Err(NetworkError::IOError(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionReset,
-                    "channel closed",
-                )))
-            }
-        };
-        let (dec_data, header, indexes) = sym_aes_decrypt(&self.header, &mut data[0..data_len])?;
-        if header.checksum() != self.header.checksum() {
-            return Err(NetworkError::ConnectionDenied(
-                "potential man in the middle attack".to_string(),
-            ));
-        }
-        outbuf.extend_from_slice(&dec_data);
-        Ok(indexes)
+        let (_, receiver) = self.query.split();
+        recv_packet(receiver, outbuf, &self.stats).await
     }
     fn header(&self) -> &StreamHeader {
         &self.header
     }
 }
 impl SllpStream {
+    /// query is connected to the socket, a session is started between the two.
+    /// the stream receives on local_id, and sends to the peer on remote_id,
+    /// initiator is true for the end that started the handshake
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_mode(
+        query: AsyncQuery<OutgoingMsg, Datagram>,
+        header: StreamHeader,
+        remote_addr: SocketAddr,
+        local_id: ConnectionId,
+        remote_id: ConnectionId,
+        initiator: bool,
+        mode: DeliveryMode,
+        context: &StreamContext,
+    ) -> Self {
+        let stats: Arc<StreamStats> = Arc::default();
+        let (query, channels) = session::spawn(
+            header.clone(),
+            remote_addr,
+            local_id,
+            remote_id,
+            initiator,
+            mode,
+            context,
+            stats.clone(),
+            query,
+        );
+        Self {
+            header,
+            query,
+            remote_addr,
+            stats,
+            mode,
+            limits: SendLimits::new(context.config().send_limit(), context.send_limit().clone()),
+            channels,
+        }
+    }
     /// reverse of into_split
     pub fn reform(send: OwnedSllpSender, recv: OwnedSllpReceiver) -> Self {
         let header = recv.header;
@@ -542,64 +1405,179 @@ impl SllpStream {
             header,
             query,
             remote_addr,
+            stats: recv.stats,
+            mode: recv.mode,
+            limits: send.limits,
+            channels: recv.channels,
         }
     }
     pub fn split(&mut self) -> (SllpSender<'_>, SllpReceiver<'_>) {
         let (sender, receiver) = self.query.split();
         (
-            SllpSender::new(&self.header, self.remote_addr, sender),
-            SllpReceiver::new(&self.header, receiver),
+            SllpSender::new(
+                &self.header,
+                self.remote_addr,
+                sender,
+                &self.stats,
+                &self.limits,
+            ),
+            SllpReceiver::new(&self.header, receiver, &self.stats),
         )
     }
+    // both halves keep talking to the same session, so they carry on where the stream left off.
+    // dropping the sender closes the stream, as dropping the stream does
     pub fn into_split(self) -> (OwnedSllpSender, OwnedSllpReceiver) {
         let (sender, receiver) = self.query.into_split();
         (
-            OwnedSllpSender::new(self.header.clone(), self.remote_addr, sender),
-            OwnedSllpReceiver::new(self.header, receiver),
+            OwnedSllpSender {
+                header: self.header.clone(),
+                remote_addr: self.remote_addr,
+                sender,
+                stats: self.stats.clone(),
+                limits: self.limits,
+                mode: self.mode,
+            },
+            OwnedSllpReceiver {
+                header: self.header,
+                receiver,
+                stats: self.stats,
+                mode: self.mode,
+                channels: self.channels,
+            },
         )
     }
+    /// sends what is still waiting, then tells the peer, whose recv returns no messages from then on.
+    /// dropping the stream does the same in the background, this waits for the peer to acknowledge it,
+    /// and fails with a timeout if it doesn't, or goes quiet for the idle timeout before everything is acknowledged
+    pub async fn close(self) -> Result<(), NetworkError> {
+        let (sender, mut receiver) = self.query.into_split();
+        drop(sender);
+        // the session lets go of its end once it is done
+        let mut result = Ok(());
+        while let Some(msg) = receiver.recv().await {
+            if let Err(NetworkError::IOError(e)) = msg {
+                if e.kind() == std::io::ErrorKind::TimedOut {
+                    result = Err(NetworkError::IOError(e));
+                }
+            }
+        }
+        check_alive(&self.stats)?;
+        result
+    }
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.mode
+    }
+    /// the next message, with what its packet said about it, None once the stream is closed.
+    /// unlike recv, messages never run together
+    pub async fn recv_msg(&mut self) -> Result<Option<Message>, NetworkError> {
+        let (_, receiver) = self.query.split();
+        recv_message(receiver, &self.stats).await
+    }
+    /// a channel to the peer inside this stream, with its own delivery mode, no handshake needed.
+    /// messages of channels with a higher priority go out first, the stream's own have STREAM_PRIORITY.
+    /// the peer gets it from accept_channel
+    /// once something is sent on it, and the stream stays open as long as any of its channels is
+    pub async fn open_channel(
+        &mut self,
+        mode: DeliveryMode,
+        priority: u8,
+    ) -> Result<SllpChannel, NetworkError> {
+        let opened = self.channels.open(mode, priority).await?;
+        Ok(self.channel(opened))
+    }
+    /// waits for the peer to open a channel, fails once the stream is closed
+    pub async fn accept_channel(&mut self) -> Result<SllpChannel, NetworkError> {
+        let accepted = self.channels.accept().await?;
+        Ok(self.channel(accepted))
+    }
+    fn channel(&self, ends: AcceptedChannel) -> SllpChannel {
+        SllpChannel::new(
+            ends,
+            self.header.clone(),
+            self.remote_addr,
+            self.stats.clone(),
+            self.limits.clone(),
+        )
+    }
+    /// largest datagram known to reach the peer, bigger messages are sent in fragments.
+    /// starts at BASE_MTU and goes up as the path is probed
+    pub fn mtu(&self) -> usize {
+        self.stats.mtu()
+    }
+    /// number of replayed packets that have been dropped
+    pub fn replays(&self) -> u64 {
+        self.stats.replays()
+    }
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
 }
 impl AsyncDataStream for SllpStream {
-    type NetStream = AsyncQuery<(Vec<u8>, SocketAddr), (Vec<u8>, usize)>;
+    type NetStream = AsyncQuery<OutgoingMsg, Datagram>;
     type StreamError = NetworkError;
     fn new(
-        query: AsyncQuery<(Vec<u8>, SocketAddr), (Vec<u8>, usize)>,
+        query: AsyncQuery<OutgoingMsg, Datagram>,
         header: StreamHeader,
         remote_addr: SocketAddr,
     ) -> Result<Self, NetworkError> {
-        Ok(Self {
-            header,
+        Ok(Self::with_mode(
             query,
+            header,
             remote_addr,
-        })
+            ConnectionId::default(),
+            ConnectionId::default(),
+            false,
+            DeliveryMode::Unordered,
+            &StreamContext::default(),
+        ))
     }
 }
 // ===================================================================================
 //                             Convenience types
 // ===================================================================================
-/// messages sent from the socket to the main program use this format
-pub type IncomingMsg = (Vec<u8>, usize);
+/// messages a session hands to its stream, one at a time and whole,
+/// errors that only concern one stream, such as a failed send, are delivered the same way
+pub type IncomingMsg = Result<Message, NetworkError>;
 /// messages sent from main to the socket use this format
 pub type OutgoingMsg = (Vec<u8>, SocketAddr);
+/// datagrams the socket routes to a stream, whole and with the address they came from
+pub type Datagram = Result<(Vec<u8>, SocketAddr), NetworkError>;
 
+/// the connection ids are the local one, then the one the peer picked
 pub type NewConnection = Result<
     (
         StreamHeader,
         SocketAddr,
-        AsyncQuery<OutgoingMsg, IncomingMsg>,
-        PubKeyComp
+        (ConnectionId, ConnectionId),
+        AsyncQuery<OutgoingMsg, Datagram>,
+        PubKeyComp,
+        DeliveryMode,
     ),
     NetworkError,
 >;
-/// a type alias, more or less for Arc<Mutex<HashMap<SocketAddr, Sender<IncomingMsg>>>>
+/// where the peer of a stream was last heard from, and the way to the stream
+pub type Route = (SocketAddr, Sender<Datagram>);
+/// a type alias, more or less for Arc<Mutex<HashMap<ConnectionId, Route>>>
 #[derive(Debug, Clone)]
 pub struct Streams {
-    value: Arc<Mutex<HashMap<SocketAddr, Sender<IncomingMsg>>>>,
+    value: Arc<Mutex<HashMap<ConnectionId, Route>>>,
 }
 impl Streams {
-    pub async fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, Sender<IncomingMsg>>> {
+    pub async fn lock(&self) -> MutexGuard<'_, HashMap<ConnectionId, Route>> {
         self.value.lock().await
     }
+    /// routes datagrams for a random connection id no stream of the socket uses, and that can't be
+    /// taken for a handshake, to sender. addr is where the peer is expected to be
+    pub async fn register(&self, addr: SocketAddr, sender: Sender<Datagram>) -> ConnectionId {
+        let mut streams = self.lock().await;
+        loop {
+            let id = ConnectionId::random();
+            if !streams.contains_key(&id) && id.to_bytes() != HANDSHAKE_MAGIC {
+                streams.insert(id, (addr, sender));
+                return id;
+            }
+        }
+    }
 }
 impl Default for Streams {
     fn default() -> Self {
@@ -607,6 +1585,25 @@ impl Default for Streams {
         Self { value }
     }
 }
+/// a handshake by the address of the peer and the connection id of the client
+pub type HandshakeKey = (SocketAddr, ConnectionId);
+/// a type alias, more or less for Arc<Mutex<HashMap<HandshakeKey, Sender<Datagram>>>>,
+/// the udp handshakes a socket started, replies are matched to them by address and connection id
+#[derive(Debug, Clone)]
+pub struct Handshakes {
+    value: Arc<Mutex<HashMap<HandshakeKey, Sender<Datagram>>>>,
+}
+impl Handshakes {
+    pub async fn lock(&self) -> MutexGuard<'_, HashMap<HandshakeKey, Sender<Datagram>>> {
+        self.value.lock().await
+    }
+}
+impl Default for Handshakes {
+    fn default() -> Self {
+        let value = Arc::new(Mutex::new(HashMap::new()));
+        Self { value }
+    }
+}
 // =================================================================
 //               Split Types for SLLP Socket
 // ==================================================================
@@ -615,17 +1612,20 @@ pub struct OwnedIncoming {
     streams: Streams,
     priv_key: RSAPrivateKey,
     receiver: Receiver<NewConnection>,
+    context: StreamContext,
 }
 impl OwnedIncoming {
     pub fn new(
         streams: Streams,
         priv_key: RSAPrivateKey,
         receiver: Receiver<NewConnection>,
+        context: StreamContext,
     ) -> Self {
         Self {
             streams,
             priv_key,
             receiver,
+            context,
         }
     }
     pub fn incoming(&mut self) -> &mut Self {
@@ -635,7 +1635,8 @@ impl OwnedIncoming {
 impl Stream for OwnedIncoming {
     type Item = Result<AsyncRequest<SllpStream>, NetworkError>;
     fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        incoming_conn(&mut self.receiver, ctx)
+        let this = &mut *self;
+        incoming_conn(&mut this.receiver, &this.context, ctx)
     }
 }
 impl Future for OwnedIncoming {
@@ -652,16 +1653,26 @@ impl Future for OwnedIncoming {
 pub struct SllpIncoming<'a> {
     priv_key: &'a RSAPrivateKey,
     receiver: &'a mut Receiver<NewConnection>,
+    context: &'a StreamContext,
 }
 impl<'a> SllpIncoming<'a> {
-    pub fn new(priv_key: &'a RSAPrivateKey, receiver: &'a mut Receiver<NewConnection>) -> Self {
-        Self { priv_key, receiver }
+    pub fn new(
+        priv_key: &'a RSAPrivateKey,
+        receiver: &'a mut Receiver<NewConnection>,
+        context: &'a StreamContext,
+    ) -> Self {
+        Self {
+            priv_key,
+            receiver,
+            context,
+        }
     }
 }
 impl<'a> Stream for SllpIncoming<'a> {
     type Item = Result<AsyncRequest<SllpStream>, NetworkError>;
     fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        incoming_conn(&mut self.receiver, ctx)
+        let this = &mut *self;
+        incoming_conn(this.receiver, this.context, ctx)
     }
 }
 impl<'a> Future for SllpIncoming<'a> {
@@ -676,79 +1687,252 @@ impl<'a> Future for SllpIncoming<'a> {
 #[derive(Debug, Clone)]
 pub struct OwnedOutgoing {
     streams: Streams,
+    handshakes: Handshakes,
     priv_key: RSAPrivateKey,
     outgoing_sender: Sender<OutgoingMsg>,
     addr: SocketAddr,
+    cipher_suite: CipherSuite,
+    handshake_mode: HandshakeMode,
+    context: StreamContext,
 }
 impl OwnedOutgoing {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         streams: Streams,
+        handshakes: Handshakes,
         priv_key: RSAPrivateKey,
         outgoing_sender: Sender<OutgoingMsg>,
         addr: SocketAddr,
+        cipher_suite: CipherSuite,
+        handshake_mode: HandshakeMode,
+        context: StreamContext,
     ) -> Self {
         Self {
             streams,
+            handshakes,
             priv_key,
             outgoing_sender,
             addr,
+            cipher_suite,
+            handshake_mode,
+            context,
         }
     }
     pub async fn connect(&self, peer: &RemotePeer) -> Result<SllpStream, NetworkError> {
-        let (incoming_sender, incoming_receiver) = channel(1);
-        let query = AsyncQuery::create(self.outgoing_sender.clone(), incoming_receiver);
-        let key = random_string(16).into_bytes();
-        let header: StreamHeader = StreamHeader::with_key(key, 0);
-
-        handshake(&header, &peer, &self.priv_key, self.addr).await?;
-
-        let stream = SllpStream::new(query, header, peer.socket_addr());
-        self.streams
-            .lock()
-            .await
-            .insert(peer.socket_addr(), incoming_sender);
-        Ok(stream?)
+        self.connect_with(peer, DeliveryMode::default()).await
+    }
+    /// the peer accepts the stream with the same delivery mode
+    pub async fn connect_with(
+        &self,
+        peer: &RemotePeer,
+        mode: DeliveryMode,
+    ) -> Result<SllpStream, NetworkError> {
+        let outgoing = SllpOutgoing::new(
+            &self.streams,
+            &self.handshakes,
+            &self.priv_key,
+            &self.outgoing_sender,
+            self.addr,
+            self.cipher_suite,
+            self.handshake_mode,
+            &self.context,
+        );
+        outgoing.connect_with(peer, mode).await
     }
 }
 /// outgoing half of SllpSocket allows for opening connections, but not listening for new ones
 #[derive(Debug, Clone)]
 pub struct SllpOutgoing<'a> {
     streams: &'a Streams,
+    handshakes: &'a Handshakes,
     priv_key: &'a RSAPrivateKey,
     outgoing_sender: &'a Sender<OutgoingMsg>,
     addr: SocketAddr,
+    cipher_suite: CipherSuite,
+    handshake_mode: HandshakeMode,
+    context: &'a StreamContext,
 }
 impl<'a> SllpOutgoing<'a> {
     /// could've been private, but functionality and transparency are important
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         streams: &'a Streams,
+        handshakes: &'a Handshakes,
         priv_key: &'a RSAPrivateKey,
         outgoing_sender: &'a Sender<OutgoingMsg>,
         addr: SocketAddr,
+        cipher_suite: CipherSuite,
+        handshake_mode: HandshakeMode,
+        context: &'a StreamContext,
     ) -> Self {
         Self {
             streams,
+            handshakes,
             priv_key,
             outgoing_sender,
             addr,
+            cipher_suite,
+            handshake_mode,
+            context,
         }
     }
     /// same as SllpSocket, couldn't find an easy way of putting it in a trait
     pub async fn connect(&self, peer: &RemotePeer) -> Result<SllpStream, NetworkError> {
-        let (incoming_sender, incoming_receiver) = channel(1);
-        let query = AsyncQuery::create(self.outgoing_sender.clone(), incoming_receiver);
-        let key = random_string(16).into_bytes();
-        let header: StreamHeader = StreamHeader::with_key(key, 0);
+        self.connect_with(peer, DeliveryMode::default()).await
+    }
+    /// the peer accepts the stream with the same delivery mode
+    pub async fn connect_with(
+        &self,
+        peer: &RemotePeer,
+        mode: DeliveryMode,
+    ) -> Result<SllpStream, NetworkError> {
+        // registered before the handshake, so nothing the peer sends as soon as it is done is missed
+        let (local_id, query) =
+            register_stream(peer.socket_addr(), self.streams, self.outgoing_sender).await;
+        let opened = open_session(
+            peer,
+            self.priv_key,
+            self.addr,
+            self.cipher_suite,
+            mode,
+            self.handshake_mode,
+            self.handshakes,
+            self.outgoing_sender,
+            local_id,
+        )
+        .await;
+        let (header, remote_id) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                self.streams.lock().await.remove(&local_id);
+                return Err(e);
+            }
+        };
+        Ok(SllpStream::with_mode(
+            query,
+            header,
+            peer.socket_addr(),
+            local_id,
+            remote_id,
+            true,
+            mode,
+            self.context,
+        ))
+    }
+}
+
+// sends the datagrams waiting for each connection in turn, so one stream with a lot queued
+// holds the others up by no more than one datagram, whether they go to the same peer or not
+async fn send_fairly(mut outgoing: Receiver<OutgoingMsg>, mut send_half: SendHalf, streams: Streams) {
+    let mut queues: HashMap<ConnectionId, VecDeque<OutgoingMsg>> = HashMap::new();
+    // connections with something queued, in the order their turns come up
+    let mut turns: VecDeque<ConnectionId> = VecDeque::new();
+    // a datagram for a connection whose queue is full. nothing more is taken from outgoing until it fits,
+    // so the sessions wait for the socket rather than their datagrams being dropped
+    let mut held: Option<OutgoingMsg> = None;
+    loop {
+        if let Some(msg) = held.take() {
+            held = enqueue(&mut queues, &mut turns, msg);
+        }
+        // with a datagram held, its connection has a turn coming up
+        if turns.is_empty() {
+            match outgoing.recv().await {
+                Some(msg) => held = enqueue(&mut queues, &mut turns, msg),
+                None => break,
+            }
+        }
+        // everything already waiting gets queued, so it is in line for a turn
+        while held.is_none() {
+            match outgoing.try_recv() {
+                Ok(msg) => held = enqueue(&mut queues, &mut turns, msg),
+                Err(_) => break,
+            }
+        }
+        let id = match turns.pop_front() {
+            Some(id) => id,
+            None => continue,
+        };
+        let (data, addr) = match queues.get_mut(&id).and_then(|queue| queue.pop_front()) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        if queues.get(&id).is_some_and(|queue| !queue.is_empty()) {
+            turns.push_back(id);
+        } else {
+            queues.remove(&id);
+        }
+        if let Err(e) = send_half.send_to(&data, &addr).await {
+            // tell the streams of the peer the packet was for
+            for (_, (_, sender)) in streams
+                .lock()
+                .await
+                .iter_mut()
+                .filter(|(_, (peer, _))| *peer == addr)
+            {
+                let _ = sender.try_send(Err(NetworkError::IOError(std::io::Error::new(
+                    e.kind(),
+                    e.to_string(),
+                ))));
+            }
+        }
+    }
+}
 
-        handshake(&header, peer, &self.priv_key, self.addr).await?;
+// gives the datagram back if the queue of its connection is full.
+// udp handshakes all start with the same magic, so they share a queue
+fn enqueue(
+    queues: &mut HashMap<ConnectionId, VecDeque<OutgoingMsg>>,
+    turns: &mut VecDeque<ConnectionId>,
+    msg: OutgoingMsg,
+) -> Option<OutgoingMsg> {
+    let id = ConnectionId::from_datagram(&msg.0).unwrap_or_default();
+    let queue = queues.entry(id).or_default();
+    if queue.len() == MAX_CONNECTION_QUEUE {
+        return Some(msg);
+    }
+    if queue.is_empty() {
+        turns.push_back(id);
+    }
+    queue.push_back(msg);
+    None
+}
 
-        let stream = SllpStream::new(query, header, peer.socket_addr());
-        self.streams
-            .lock()
-            .await
-            .insert(peer.socket_addr(), incoming_sender);
-        Ok(stream?)
+// sets the don't fragment bit, and leaves the mtu to the sessions of the streams
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, ipv6: bool) -> std::io::Result<()> {
+    let (level, name, value) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE)
+    };
+    set_option(socket, level, name, value)
+}
+// every stream of the socket shares its receive buffer, the kernel caps it at net.core.rmem_max
+#[cfg(target_os = "linux")]
+fn set_recv_buffer(socket: &UdpSocket) -> std::io::Result<()> {
+    set_option(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, RECV_BUFFER_LEN)
+}
+#[cfg(target_os = "linux")]
+fn set_option(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
     }
+    Ok(())
 }
 
 // =====================================================================
@@ -757,15 +1941,21 @@ impl<'a> SllpOutgoing<'a> {
 /// this structure provides an alternative to TCP Networking, but is not connectionless
 /// while this structure uses an owned UdpSocket for networking, it also maintains a connection through the standard means that this crate provides
 /// this is offered as a way to increase the efficiency of the network of TCP at the cost of a lack of garuntee of packet order
-/// future implementations may implement a system of dropping out dated packets
+/// streams that only care about the newest data can drop out dated packets instead, see DeliveryMode::LatestOnly
 #[derive(Debug)]
 pub struct SllpSocket {
     priv_key: RSAPrivateKey,
     receiver: Receiver<NewConnection>,
     streams: Streams,
+    /// udp handshakes this socket started, by the address of the peer and the connection id
+    handshakes: Handshakes,
     outgoing_sender: Sender<OutgoingMsg>,
     addr: SocketAddr,
+    handshake_addr: Option<SocketAddr>,
     client_only: bool,
+    cipher_suite: CipherSuite,
+    handshake_mode: HandshakeMode,
+    context: StreamContext,
 }
 #[async_trait]
 impl AsyncNetworkHost for SllpSocket {
@@ -781,98 +1971,233 @@ impl SllpSocket {
     async fn initialize(config: &ArtificeConfig, client_only: bool) -> Result<Self, NetworkError> {
         let data = config.host_data();
         let priv_key_comp = data.privkeycomp();
-        let socket_addr: SocketAddr = config.socket_addr().into();
-        println!("socket addr: {}", socket_addr);
         let priv_key: RSAPrivateKey = priv_key_comp.into();
         // centralized udp socket, that data is routed through
-        let socket = UdpSocket::bind(socket_addr).await?;
+        let socket = UdpSocket::bind(SocketAddr::from(config.socket_addr())).await?;
+        // the bound address, which differs from the configured one when port 0 was requested
+        let socket_addr = socket.local_addr()?;
+        // so mtu probes that are too big get dropped, rather than split up by ip and counted as a success
+        #[cfg(target_os = "linux")]
+        set_dont_fragment(&socket, socket_addr.is_ipv6())?;
+        // so a burst of fragments isn't dropped before the socket gets to it
+        #[cfg(target_os = "linux")]
+        set_recv_buffer(&socket)?;
         let (mut request_sender, request_receiver): (
             Sender<NewConnection>,
             Receiver<NewConnection>,
         ) = channel(200);
-        let (outgoing_sender, mut outgoing_receiver): (
-            Sender<OutgoingMsg>,
-            Receiver<(Vec<u8>, SocketAddr)>,
-        ) = channel(200);
+        let (outgoing_sender, outgoing_receiver): (Sender<OutgoingMsg>, Receiver<OutgoingMsg>) =
+            channel(200);
         let senders: Streams = Streams::default();
-        let (mut recv_half, mut send_half) = socket.split();
+        let handshakes: Handshakes = Handshakes::default();
+        let handshake_mode = config.handshake_mode();
+        // udp handshakes from new peers, only accepted when the socket listens in udp mode
+        let (mut udp_listener, handshake_datagrams) =
+            if !client_only && handshake_mode == HandshakeMode::Udp {
+                let (sender, receiver) = channel(STREAM_CHANNEL_LEN);
+                (Some(sender), Some(receiver))
+            } else {
+                (None, None)
+            };
+        let (mut recv_half, send_half) = socket.split();
+        // socket level errors are reported on incoming(), which client only sockets don't have
+        let mut error_sender = if client_only {
+            None
+        } else {
+            Some(request_sender.clone())
+        };
         // spawn incoming
         let streams = senders.clone();
+        let client_handshakes = handshakes.clone();
         let out_sender = outgoing_sender.clone();
+        let mut peer_limits = PeerLimiters::new(config.recv_limit());
         tokio::spawn(async move {
             loop {
                 let mut buffer: [u8; 65535] = [0; 65535];
-                match recv_half.recv_from(&mut buffer).await {
+                let received = recv_half.recv_from(&mut buffer).await;
+                // checked before anything else, handshakes included, so a flood costs as little as possible
+                if let Ok((data_len, addr)) = received {
+                    match peer_limits.admit(addr, data_len, Instant::now()) {
+                        Admission::Accepted => (),
+                        Admission::Limited => {
+                            // told once, not for every datagram dropped
+                            for (_, (_, sender)) in streams
+                                .lock()
+                                .await
+                                .iter_mut()
+                                .filter(|(_, (peer, _))| *peer == addr)
+                            {
+                                let _ = sender.try_send(Err(NetworkError::RateLimited(format!(
+                                    "{} is over the receive limit of {:?}",
+                                    addr,
+                                    peer_limits.limit()
+                                ))));
+                            }
+                            continue;
+                        }
+                        Admission::Dropped => continue,
+                    }
+                }
+                match received {
+                    // stream data starting with the magic by chance, a 1 in 2^64 event, is dropped
+                    Ok((data_len, addr)) if HandshakeMsg::is_handshake(&buffer[0..data_len]) => {
+                        let data = buffer[0..data_len].to_vec();
+                        // replies to handshakes this socket started, otherwise a peer starting one
+                        let key = HandshakeMsg::connection_id(&data).map(|id| (addr, id));
+                        let mut started = client_handshakes.lock().await;
+                        if let Some(sender) = key.and_then(|key| started.get_mut(&key)) {
+                            let _ = sender.try_send(Ok((data, addr)));
+                        } else if let Some(sender) = udp_listener.as_mut() {
+                            let _ = sender.try_send((data, addr));
+                        }
+                    }
                     Ok((data_len, addr)) => {
-                        //println!("got message for: {}", addr);
+                        let data = &buffer[0..data_len];
+                        let id = match ConnectionId::from_datagram(data) {
+                            Some(id) => id,
+                            None => continue,
+                        };
                         let mut senders = streams.lock().await;
-                        // check if they are authorized, but if someone tries to spoof
-                        // IP it wo't matter because of encryption they will get garbage.
-                        if let Some(sender) = senders.get_mut(&addr) {
-                            sender
-                                .send((buffer[0..data_len].to_vec(), data_len))
-                                .await
-                                .unwrap();
+                        // routed by connection id, not address, so a stream follows its peer to a new one.
+                        // anyone can put a valid id in front of garbage, the stream drops what doesn't decrypt
+                        let closed = match senders.get_mut(&id) {
+                            Some((_, sender)) => {
+                                match sender.try_send(Ok((data.to_vec(), addr))) {
+                                    Ok(()) => false,
+                                    // the stream isn't keeping up, so the datagram is dropped
+                                    // rather than holding up every other stream
+                                    Err(TrySendError::Full(_)) => false,
+                                    Err(TrySendError::Closed(_)) => true,
+                                }
+                            }
+                            None => false,
+                        };
+                        if closed {
+                            senders.remove(&id);
+                        }
+                    }
+                    // such as icmp port unreachable from a peer that went away,
+                    // the socket is still usable for every other peer
+                    Err(e) => {
+                        if let Some(sender) = error_sender.as_mut() {
+                            let _ = sender.try_send(Err(e.into()));
                         }
                     }
-                    Err(e) => panic!("error: {}", e),
                 }
             }
         });
-        // spawn outgoing
-        tokio::spawn(async move {
-            loop {
-                let (out_data, remote_addr) = outgoing_receiver.recv().await.unwrap();
-                send_half.send_to(&out_data, &remote_addr).await.unwrap();
-            }
-        });
-        if !client_only {
+        // spawn outgoing, runs until every sender has been dropped
+        tokio::spawn(send_fairly(outgoing_receiver, send_half, senders.clone()));
+        let mut handshake_addr = None;
+        if let Some(datagrams) = handshake_datagrams {
+            // handshakes share the udp socket, so that is where peers send them
+            handshake_addr = Some(socket_addr);
+            tokio::spawn(udp_handshake_listener(
+                datagrams,
+                priv_key.clone(),
+                senders.clone(),
+                outgoing_sender.clone(),
+                request_sender.clone(),
+                config.cipher_suite(),
+            ));
+        } else if !client_only {
             // spawn tcp listener to wait for incoming connections
-            let mut listener = TcpListener::bind("0.0.0.0:6432").await?;
+            let mut listener = TcpListener::bind(SocketAddr::from(config.handshake_addr())).await?;
+            handshake_addr = Some(listener.local_addr()?);
             let in_priv_key = priv_key.clone();
             let in_senders = senders.clone();
+            let min_suite = config.cipher_suite();
             // checks for new incoming connections
             // note connections must be initiated by using a tcp stream
             tokio::spawn(async move {
                 loop {
-                    request_sender
-                        .send(
-                            recv_incoming(
-                                &mut listener,
-                                &in_priv_key,
-                                &in_senders,
-                                &outgoing_sender,
-                            )
-                            .await,
+                    let (stream, tcpaddr) = match listener.accept().await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            if request_sender.send(Err(e.into())).await.is_err() {
+                                // the socket was dropped, so no one is listening anymore
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    // each handshake runs on its own, so a slow or malicious peer can't hold up the rest
+                    let mut request_sender = request_sender.clone();
+                    let in_priv_key = in_priv_key.clone();
+                    let in_senders = in_senders.clone();
+                    let outgoing_sender = outgoing_sender.clone();
+                    tokio::spawn(async move {
+                        let result = recv_incoming(
+                            stream,
+                            tcpaddr,
+                            &in_priv_key,
+                            &in_senders,
+                            &outgoing_sender,
+                            min_suite,
                         )
-                        .await
-                        .unwrap();
+                        .await;
+                        // fails only if the socket was dropped, leaving no one to tell
+                        let _ = request_sender.send(result).await;
+                    });
                 }
             });
         }
 
+        let context = StreamContext::new(
+            config.stream_config().clone(),
+            SharedLimiter::new(config.send_limit()),
+            senders.clone(),
+        );
         Ok(Self {
             priv_key,
             receiver: request_receiver,
             streams: senders,
+            handshakes,
             outgoing_sender: out_sender,
             addr: socket_addr,
+            handshake_addr,
             client_only,
+            cipher_suite: config.cipher_suite(),
+            handshake_mode,
+            context,
         })
     }
     pub async fn connect(&self, peer: &RemotePeer) -> Result<SllpStream, NetworkError> {
-        let (incoming_sender, incoming_receiver) = channel(1);
-        let query = AsyncQuery::create(self.outgoing_sender.clone(), incoming_receiver);
-        let key = random_string(16).into_bytes();
-        let header: StreamHeader = StreamHeader::with_key(key, 0);
-        handshake(&header, peer, &self.priv_key, self.addr).await?;
-
-        let stream = SllpStream::new(query, header, peer.socket_addr());
-        self.streams
-            .lock()
-            .await
-            .insert(peer.socket_addr(), incoming_sender);
-        Ok(stream?)
+        self.connect_with(peer, DeliveryMode::default()).await
+    }
+    /// the peer accepts the stream with the same delivery mode
+    pub async fn connect_with(
+        &self,
+        peer: &RemotePeer,
+        mode: DeliveryMode,
+    ) -> Result<SllpStream, NetworkError> {
+        let outgoing = SllpOutgoing::new(
+            &self.streams,
+            &self.handshakes,
+            &self.priv_key,
+            &self.outgoing_sender,
+            self.addr,
+            self.cipher_suite,
+            self.handshake_mode,
+            &self.context,
+        );
+        outgoing.connect_with(peer, mode).await
+    }
+    /// address of the udp socket stream data is sent from
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// address incoming handshakes are accepted on, None for client only sockets
+    pub fn handshake_addr(&self) -> Option<SocketAddr> {
+        self.handshake_addr
+    }
+    /// how other peers should address this socket, only meaningful if it was bound to a specific ip
+    pub fn remote_peer(&self) -> RemotePeer {
+        let pubkey = PubKeyComp::from(&self.priv_key);
+        match self.handshake_addr {
+            Some(addr) => RemotePeer::with_handshake_port(self.addr.into(), addr.port(), pubkey),
+            None => RemotePeer::new(self.addr.into(), pubkey),
+        }
     }
     pub fn split(&mut self) -> Result<(SllpOutgoing<'_>, SllpIncoming<'_>), NetworkError> {
         if self.client_only {
@@ -881,11 +2206,19 @@ impl SllpSocket {
         Ok((
             SllpOutgoing::new(
                 &self.streams,
+                &self.handshakes,
                 &self.priv_key,
                 &self.outgoing_sender,
                 self.addr,
+                self.cipher_suite,
+                self.handshake_mode,
+                &self.context,
+            ),
+            SllpIncoming::new(
+                &self.priv_key,
+                &mut self.receiver,
+                &self.context,
             ),
-            SllpIncoming::new(&self.priv_key, &mut self.receiver),
         ))
     }
     pub fn into_split(self) -> Result<(OwnedOutgoing, OwnedIncoming), NetworkError> {
@@ -895,11 +2228,20 @@ impl SllpSocket {
         Ok((
             OwnedOutgoing::new(
                 self.streams.clone(),
+                self.handshakes,
                 self.priv_key.clone(),
                 self.outgoing_sender,
                 self.addr,
+                self.cipher_suite,
+                self.handshake_mode,
+                self.context.clone(),
+            ),
+            OwnedIncoming::new(
+                self.streams,
+                self.priv_key,
+                self.receiver,
+                self.context,
             ),
-            OwnedIncoming::new(self.streams, self.priv_key, self.receiver),
         ))
     }
     pub fn incoming(&mut self) -> &mut Self {
@@ -909,7 +2251,8 @@ impl SllpSocket {
 impl Stream for SllpSocket {
     type Item = Result<AsyncRequest<SllpStream>, NetworkError>;
     fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        incoming_conn(&mut self.receiver, ctx)
+        let this = &mut *self;
+        incoming_conn(&mut this.receiver, &this.context, ctx)
     }
 }
 impl Future for SllpSocket {
@@ -921,3 +2264,481 @@ impl Future for SllpSocket {
         }
     }
 }
+
+// =====================================================================
+//                              Tests
+// =====================================================================
+/// a client claiming someone elses public key can't produce the signature to go with it
+#[tokio::test]
+async fn forged_client_identity() {
+    use futures::StreamExt;
+    let mut server_config = ArtificeConfig::generate(SocketAddr::from(([127, 0, 0, 1], 0)).into());
+    server_config.set_handshake_addr(SocketAddr::from(([127, 0, 0, 1], 0)));
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+
+    let victim = PrivKeyComp::generate().unwrap();
+    let attacker = RSAPrivateKey::from(&PrivKeyComp::generate().unwrap());
+    let ephemeral = EphemeralKey::generate();
+    let client_hello = serde_json::to_vec(&ClientHello::new(
+        RemotePeer::new(
+            SocketAddr::from(([127, 0, 0, 1], 7010)).into(),
+            PubKeyComp::from(&victim),
+        ),
+        CipherSuite::default(),
+        ephemeral.public_bytes(),
+        DeliveryMode::default(),
+        ConnectionId::random(),
+    ))
+    .unwrap();
+    let mut tcpstream = TcpStream::connect(server.handshake_addr().unwrap())
+        .await
+        .unwrap();
+    write_frame(&mut tcpstream, &client_hello).await.unwrap();
+    let server_hello = read_frame(&mut tcpstream).await.unwrap();
+    read_frame(&mut tcpstream).await.unwrap();
+    let signature = sign_transcript(
+        &attacker,
+        &transcript_hash(CLIENT_LABEL, &[&client_hello, &server_hello]),
+    )
+    .unwrap();
+    write_frame(&mut tcpstream, &signature).await.unwrap();
+
+    // the server refuses the connection instead of confirming the session
+    assert!(read_frame(&mut tcpstream).await.is_err());
+    assert!(server.next().await.unwrap().is_err());
+}
+
+/// udp handshakes need a cookie before the server does any work, and repeated messages get the same answer
+#[tokio::test]
+async fn udp_handshake_cookie() {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut server_config = ArtificeConfig::generate(addr.into());
+    server_config.set_handshake_mode(HandshakeMode::Udp);
+    let server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    assert_eq!(server.handshake_addr(), Some(server.local_addr()));
+
+    let mut client = UdpSocket::bind(addr).await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let client_key = PrivKeyComp::generate().unwrap();
+    let ephemeral = EphemeralKey::generate();
+    let hello = serde_json::to_vec(&ClientHello::new(
+        RemotePeer::new(client_addr.into(), PubKeyComp::from(&client_key)),
+        CipherSuite::default(),
+        ephemeral.public_bytes(),
+        DeliveryMode::default(),
+        ConnectionId::new(7),
+    ))
+    .unwrap();
+    let server_addr = server.local_addr();
+    async fn exchange(
+        client: &mut UdpSocket,
+        server_addr: SocketAddr,
+        request: HandshakeMsg,
+    ) -> HandshakeMsg {
+        let id = ConnectionId::new(7);
+        let mut buffer = vec![0u8; 65535];
+        client.send_to(&request.to_raw(id), server_addr).await.unwrap();
+        let len = timeout(HANDSHAKE_TIMEOUT, client.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        // the answer is for the same handshake
+        let (reply_id, reply) = HandshakeMsg::from_raw(&buffer[0..len]).unwrap();
+        assert_eq!(reply_id, id);
+        reply
+    }
+
+    // no cookie, or a forged one, only gets a retry
+    let cookie = match exchange(&mut client, server_addr, HandshakeMsg::ClientHello {
+        cookie: Vec::new(),
+        hello: hello.clone(),
+    })
+    .await
+    {
+        HandshakeMsg::Retry { cookie } => cookie,
+        other => panic!("expected a retry, got {:?}", other),
+    };
+    let mut forged = cookie.clone();
+    forged[10] ^= 1;
+    match exchange(&mut client, server_addr, HandshakeMsg::ClientHello {
+        cookie: forged,
+        hello: hello.clone(),
+    })
+    .await
+    {
+        HandshakeMsg::Retry { .. } => (),
+        other => panic!("forged cookie accepted: {:?}", other),
+    }
+    // a valid cookie gets a server hello, and a retransmission gets the same one
+    let request = HandshakeMsg::ClientHello { cookie, hello };
+    let first = exchange(&mut client, server_addr, request.clone()).await;
+    assert!(matches!(first, HandshakeMsg::ServerHello { .. }));
+    assert_eq!(exchange(&mut client, server_addr, request).await, first);
+}
+
+// the next data packet a stream sent, skipping the probes its session sends on its own
+#[cfg(test)]
+async fn next_data_packet(
+    outgoing: &mut Receiver<OutgoingMsg>,
+    header: &StreamHeader,
+) -> (Vec<u8>, SocketAddr) {
+    loop {
+        let (packet, addr) = outgoing.recv().await.unwrap();
+        let (cid, sealed) = packet.split_at(CID_LEN);
+        let (_, remote_header, _) = sym_aes_decrypt(header, cid, &mut sealed.to_vec()).unwrap();
+        if remote_header.packet_type() == PacketType::RawData {
+            return (packet, addr);
+        }
+    }
+}
+
+// the next path challenge or response sent to addr, looped back the stream answers its own
+#[cfg(test)]
+async fn next_path_packet(
+    outgoing: &mut Receiver<OutgoingMsg>,
+    header: &StreamHeader,
+    addr: SocketAddr,
+) -> Vec<u8> {
+    use crate::protocol::AdminMsg;
+    loop {
+        let (packet, to) = outgoing.recv().await.unwrap();
+        let (cid, sealed) = packet.split_at(CID_LEN);
+        let (payload, _, _) = sym_aes_decrypt(header, cid, &mut sealed.to_vec()).unwrap();
+        let path = matches!(
+            AdminMsg::from_raw(&payload),
+            Ok(AdminMsg::PathChallenge { .. }) | Ok(AdminMsg::PathResponse { .. })
+        );
+        if path && to == addr {
+            return packet;
+        }
+    }
+}
+
+/// both ends stop routing datagrams to a stream once it is closed
+#[tokio::test]
+async fn closed_streams_are_unregistered() {
+    use futures::StreamExt;
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut server_config = ArtificeConfig::generate(addr.into());
+    server_config.set_handshake_addr(addr);
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let client = SllpSocket::client_only(&ArtificeConfig::generate(addr.into()))
+        .await
+        .unwrap();
+    // the stream is registered for the handshake, and no longer once it fails
+    let impostor = RemotePeer::with_handshake_port(
+        server.local_addr().into(),
+        server.handshake_addr().unwrap().port(),
+        PubKeyComp::from(&client.priv_key),
+    );
+    assert!(client.connect(&impostor).await.is_err());
+    assert!(client.streams.lock().await.is_empty());
+    assert!(server.next().await.unwrap().is_err());
+    let stream = client.connect(&server.remote_peer()).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    assert_eq!(client.streams.lock().await.len(), 1);
+    assert_eq!(server.streams.lock().await.len(), 1);
+    stream.close().await.unwrap();
+    assert!(client.streams.lock().await.is_empty());
+    let mut inbuf = Vec::new();
+    assert!(accepted.recv(&mut inbuf).await.unwrap().is_empty());
+    // the server waits a while in case its acknowledgement was lost
+    for _ in 0..100 {
+        if server.streams.lock().await.is_empty() {
+            return;
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("the server still routes to the closed stream");
+}
+
+/// a peer that stops answering is given up on, both ways through the stream fail with a timeout
+#[tokio::test]
+async fn dead_peers_time_out() {
+    use crate::protocol::AdminMsg;
+    use std::time::Duration;
+    let header = StreamHeader::new(0);
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 7023));
+    let (outgoing_sender, mut outgoing_receiver) = channel(STREAM_CHANNEL_LEN);
+    let (incoming_sender, incoming_receiver) = channel(STREAM_CHANNEL_LEN);
+    let mut config = StreamConfig::default();
+    config.set_keepalive(Some(Duration::from_millis(50)));
+    config.set_idle_timeout(Some(Duration::from_millis(300)));
+    let context = StreamContext::new(config, SharedLimiter::default(), Streams::default());
+    let local_id = context
+        .streams()
+        .register(remote_addr, incoming_sender)
+        .await;
+    let mut stream = SllpStream::with_mode(
+        AsyncQuery::create(outgoing_sender, incoming_receiver),
+        header.clone(),
+        remote_addr,
+        local_id,
+        ConnectionId::random(),
+        false,
+        DeliveryMode::Unordered,
+        &context,
+    );
+    // the silence is answered with pings, among the probes
+    loop {
+        let (mut packet, _) = outgoing_receiver.recv().await.unwrap();
+        let (cid, sealed) = packet.split_at_mut(CID_LEN);
+        let (payload, _, _) = sym_aes_decrypt(&header, cid, sealed).unwrap();
+        if AdminMsg::from_raw(&payload).unwrap() == AdminMsg::Ping {
+            break;
+        }
+    }
+    let timed_out = |error: NetworkError| match error {
+        NetworkError::IOError(e) => e.kind() == std::io::ErrorKind::TimedOut,
+        _ => false,
+    };
+    let mut inbuf = Vec::new();
+    let result = timeout(Duration::from_secs(5), stream.recv(&mut inbuf))
+        .await
+        .unwrap();
+    assert!(timed_out(result.unwrap_err()));
+    assert!(timed_out(stream.send(b"anyone there").await.unwrap_err()));
+    assert_eq!(stream.stats().liveness(), LivenessState::Dead);
+    // and the socket no longer routes to it
+    for _ in 0..50 {
+        if context.streams().lock().await.is_empty() {
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+    panic!("the stream was never evicted");
+}
+
+/// a captured datagram injected again is dropped and counted, on the stream and on both kinds of split
+#[tokio::test]
+async fn replayed_packets_are_dropped() {
+    use std::time::Duration;
+    let header = StreamHeader::new(0);
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 7020));
+    let (outgoing_sender, mut outgoing_receiver) = channel(STREAM_CHANNEL_LEN);
+    let (mut incoming_sender, incoming_receiver) = channel(STREAM_CHANNEL_LEN);
+    let mut stream = SllpStream::new(
+        AsyncQuery::create(outgoing_sender, incoming_receiver),
+        header.clone(),
+        remote_addr,
+    )
+    .unwrap();
+    // loop what the stream sends back into it, so it receives its own packets
+    stream.send(b"first").await.unwrap();
+    let first = next_data_packet(&mut outgoing_receiver, &header).await;
+    stream.send(b"second").await.unwrap();
+    let second = next_data_packet(&mut outgoing_receiver, &header).await;
+    for packet in &[&first, &first, &second] {
+        incoming_sender.try_send(Ok((*packet).clone())).unwrap();
+    }
+    let mut inbuf = Vec::new();
+    stream.recv(&mut inbuf).await.unwrap();
+    stream.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"firstsecond");
+    assert_eq!(stream.replays(), 1);
+
+    let short = Duration::from_millis(100);
+    {
+        let (_, mut receiver) = stream.split();
+        incoming_sender.try_send(Ok(first.clone())).unwrap();
+        assert!(timeout(short, receiver.recv(&mut inbuf)).await.is_err());
+        assert_eq!(receiver.replays(), 2);
+        incoming_sender.try_send(Ok(second.clone())).unwrap();
+        assert!(timeout(short, receiver.recv(&mut inbuf)).await.is_err());
+        assert_eq!(receiver.replays(), 3);
+    }
+    let (mut sender, mut receiver) = stream.into_split();
+    incoming_sender.try_send(Ok(first)).unwrap();
+    assert!(timeout(short, receiver.recv(&mut inbuf)).await.is_err());
+    assert_eq!(receiver.replays(), 4);
+    // the split sender continues the numbering, so its packets are still accepted
+    sender.send(b"third").await.unwrap();
+    let third = next_data_packet(&mut outgoing_receiver, &header).await;
+    incoming_sender.try_send(Ok(third)).unwrap();
+    receiver.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"firstsecondthird");
+
+    // one that doesn't decrypt is only counted, the stream isn't told
+    sender.send(b"fourth").await.unwrap();
+    let (mut tampered, addr) = next_data_packet(&mut outgoing_receiver, &header).await;
+    *tampered.last_mut().unwrap() ^= 1;
+    incoming_sender.try_send(Ok((tampered, addr))).unwrap();
+    assert!(timeout(short, receiver.recv(&mut inbuf)).await.is_err());
+    assert_eq!(receiver.stats().forgeries(), 1);
+}
+
+/// the stream only moves to a new address once the peer answers a challenge there
+#[tokio::test]
+async fn migration_waits_for_the_new_path() {
+    let header = StreamHeader::new(0);
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 7024));
+    let moved_addr = SocketAddr::from(([127, 0, 0, 1], 7025));
+    let (outgoing_sender, mut outgoing_receiver) = channel(STREAM_CHANNEL_LEN);
+    let (mut incoming_sender, incoming_receiver) = channel(STREAM_CHANNEL_LEN);
+    let mut stream = SllpStream::new(
+        AsyncQuery::create(outgoing_sender, incoming_receiver),
+        header.clone(),
+        remote_addr,
+    )
+    .unwrap();
+    stream.send(b"first").await.unwrap();
+    let first = next_data_packet(&mut outgoing_receiver, &header).await;
+    incoming_sender.try_send(Ok((first.0, moved_addr))).unwrap();
+    let mut inbuf = Vec::new();
+    stream.recv(&mut inbuf).await.unwrap();
+    let challenge = next_path_packet(&mut outgoing_receiver, &header, moved_addr).await;
+    // until it is answered everything still goes the old way
+    stream.send(b"second").await.unwrap();
+    let (_, to) = next_data_packet(&mut outgoing_receiver, &header).await;
+    assert_eq!(to, remote_addr);
+    assert_eq!(stream.stats().migrations(), 0);
+    incoming_sender
+        .try_send(Ok((challenge, moved_addr)))
+        .unwrap();
+    let response = next_path_packet(&mut outgoing_receiver, &header, moved_addr).await;
+    incoming_sender
+        .try_send(Ok((response, moved_addr)))
+        .unwrap();
+    while stream.stats().migrations() == 0 {
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+    }
+    stream.send(b"third").await.unwrap();
+    let (_, to) = next_data_packet(&mut outgoing_receiver, &header).await;
+    assert_eq!(to, moved_addr);
+}
+
+#[test]
+fn connection_id_is_authenticated() {
+    let header = StreamHeader::new(0);
+    let packet = seal(
+        &header,
+        &SeqCounter::default(),
+        ConnectionId::new(7),
+        PacketType::RawData,
+        b"only for stream 7",
+    );
+    // a packet moved in front of another stream's id must not decrypt there
+    for index in 0..CID_LEN {
+        let mut moved = packet.clone();
+        moved[index] ^= 1;
+        let (cid, sealed) = moved.split_at_mut(CID_LEN);
+        match sym_aes_decrypt(&header, cid, sealed) {
+            Err(NetworkError::AuthenticationFailed) => (),
+            other => panic!("flipped id byte {} was accepted: {:?}", index, other),
+        }
+    }
+    let mut packet = packet;
+    let (cid, sealed) = packet.split_at_mut(CID_LEN);
+    assert_eq!(
+        sym_aes_decrypt(&header, cid, sealed).unwrap().0,
+        b"only for stream 7"
+    );
+}
+
+#[tokio::test]
+async fn reliable_stream_survives_loss() {
+    let header = StreamHeader::new(0);
+    let addr = SocketAddr::from(([127, 0, 0, 1], 7021));
+    // every third packet in each direction is lost, acknowledgements included
+    fn lossy(
+        mut from: Receiver<OutgoingMsg>,
+        mut to: Sender<Datagram>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut count = 0;
+            while let Some((packet, addr)) = from.recv().await {
+                count += 1;
+                if count % 3 != 0 {
+                    let _ = to.send(Ok((packet, addr))).await;
+                }
+            }
+        })
+    }
+    let (a_out, a_net) = channel(STREAM_CHANNEL_LEN);
+    let (b_out, b_net) = channel(STREAM_CHANNEL_LEN);
+    let (a_in_sender, a_in) = channel(STREAM_CHANNEL_LEN);
+    let (b_in_sender, b_in) = channel(STREAM_CHANNEL_LEN);
+    lossy(a_net, b_in_sender);
+    lossy(b_net, a_in_sender);
+    let mode = DeliveryMode::Reliable;
+    let context = StreamContext::default();
+    let a_query = AsyncQuery::create(a_out, a_in);
+    let b_query = AsyncQuery::create(b_out, b_in);
+    let (a_id, b_id) = (ConnectionId::new(1), ConnectionId::new(2));
+    let mut a =
+        SllpStream::with_mode(a_query, header.clone(), addr, a_id, b_id, true, mode, &context);
+    let mut b = SllpStream::with_mode(b_query, header, addr, b_id, a_id, false, mode, &context);
+    for i in 0..50u32 {
+        a.send(&i.to_be_bytes()).await.unwrap();
+    }
+    for i in 0..50u32 {
+        let mut inbuf = Vec::new();
+        timeout(std::time::Duration::from_secs(30), b.recv(&mut inbuf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inbuf, i.to_be_bytes());
+    }
+    assert!(a.stats().retransmits() > 0);
+    assert!(a.stats().rtt().is_some());
+}
+
+#[tokio::test]
+async fn latest_only_drops_stale_packets() {
+    let header = StreamHeader::new(0);
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 7022));
+    let (outgoing_sender, mut outgoing_receiver) = channel(STREAM_CHANNEL_LEN);
+    let (mut incoming_sender, incoming_receiver) = channel(STREAM_CHANNEL_LEN);
+    let mut stream = SllpStream::with_mode(
+        AsyncQuery::create(outgoing_sender, incoming_receiver),
+        header.clone(),
+        remote_addr,
+        ConnectionId::default(),
+        ConnectionId::default(),
+        false,
+        DeliveryMode::LatestOnly,
+        &StreamContext::default(),
+    );
+    let mut packets = Vec::new();
+    for data in &[b"first", b"secnd", b"third"] {
+        stream.send(*data).await.unwrap();
+        packets.push(next_data_packet(&mut outgoing_receiver, &header).await);
+    }
+    // reordered on the way, then the first one is replayed
+    for i in &[1, 0, 2, 0] {
+        incoming_sender.try_send(Ok(packets[*i].clone())).unwrap();
+    }
+    let mut inbuf = Vec::new();
+    stream.recv(&mut inbuf).await.unwrap();
+    stream.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"secndthird");
+    let short = std::time::Duration::from_millis(100);
+    assert!(timeout(short, stream.recv(&mut inbuf)).await.is_err());
+    assert_eq!(stream.stats().stale(), 1);
+    assert_eq!(stream.replays(), 1);
+}
+#[tokio::test]
+async fn owned_halves_see_the_session_die() {
+    let header = StreamHeader::new(0);
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 7023));
+    let stats: Arc<StreamStats> = Arc::default();
+    // the session is gone, having given up on the peer
+    let (outgoing_sender, _) = channel(STREAM_CHANNEL_LEN);
+    let (_, incoming_receiver) = channel(STREAM_CHANNEL_LEN);
+    stats.set_liveness(LivenessState::Dead);
+    let mode = DeliveryMode::Unordered;
+    let mut sender = OwnedSllpSender::new(
+        header.clone(),
+        remote_addr,
+        outgoing_sender,
+        stats.clone(),
+        mode,
+    );
+    let mut receiver = OwnedSllpReceiver::new(header, incoming_receiver, stats, mode);
+    let timed_out = |result: Result<_, NetworkError>| match result {
+        Err(NetworkError::IOError(e)) => e.kind() == std::io::ErrorKind::TimedOut,
+        _ => false,
+    };
+    assert!(timed_out(sender.send(b"anyone there").await.map(|_| ())));
+    assert!(timed_out(receiver.recv_msg().await.map(|_| ())));
+}
@@ -0,0 +1,120 @@
+//! keepalives and dead peer detection. anything authenticated from the peer shows it is still there,
+//! after the keepalive interval goes by in silence the stream pings it, and once nothing has been heard
+//! for the idle timeout the peer is taken to be gone and the stream is closed
+use std::time::{Duration, Instant};
+
+/// silence before a stream pings its peer, unless configured otherwise
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(10);
+/// silence before a stream gives up on its peer, unless configured otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// what a stream knows about its peer, see StreamStats::liveness
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LivenessState {
+    /// heard from within the keepalive interval
+    Alive,
+    /// pinged, and not heard from since
+    Probing,
+    /// nothing heard for the idle timeout, sending and receiving fail with a timeout from then on
+    Dead,
+}
+
+#[derive(Debug)]
+pub struct Liveness {
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    last_heard: Instant,
+    // the last ping not answered yet
+    last_ping: Option<Instant>,
+    dead: bool,
+}
+impl Liveness {
+    /// None for either turns it off, without an idle timeout a peer is never given up on
+    pub fn new(keepalive: Option<Duration>, idle_timeout: Option<Duration>, now: Instant) -> Self {
+        Self {
+            keepalive,
+            idle_timeout,
+            last_heard: now,
+            last_ping: None,
+            dead: false,
+        }
+    }
+    pub fn state(&self) -> LivenessState {
+        if self.dead {
+            LivenessState::Dead
+        } else if self.last_ping.is_some() {
+            LivenessState::Probing
+        } else {
+            LivenessState::Alive
+        }
+    }
+    /// a packet from the peer got through
+    pub fn on_heard(&mut self, now: Instant) {
+        if !self.dead {
+            self.last_heard = now;
+            self.last_ping = None;
+        }
+    }
+    /// when poll has something to do, None if never
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.dead {
+            return None;
+        }
+        let ping = self
+            .keepalive
+            .map(|keepalive| self.last_ping.unwrap_or(self.last_heard) + keepalive);
+        let expiry = self.idle_timeout.map(|timeout| self.last_heard + timeout);
+        ping.into_iter().chain(expiry).min()
+    }
+    /// true if a ping should be sent now, while unanswered one is sent every keepalive interval
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.dead {
+            return false;
+        }
+        if self
+            .idle_timeout
+            .is_some_and(|timeout| now >= self.last_heard + timeout)
+        {
+            self.dead = true;
+            return false;
+        }
+        match self.keepalive {
+            Some(keepalive) if now >= self.last_ping.unwrap_or(self.last_heard) + keepalive => {
+                self.last_ping = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn liveness_test() {
+    let second = Duration::from_secs(1);
+    let now = Instant::now();
+    let mut liveness = Liveness::new(Some(second), Some(3 * second), now);
+    assert!(!liveness.poll(now));
+    assert_eq!(liveness.deadline(), Some(now + second));
+    // silence is answered with a ping every interval
+    assert!(liveness.poll(now + second));
+    assert_eq!(liveness.state(), LivenessState::Probing);
+    assert!(!liveness.poll(now + second));
+    assert!(liveness.poll(now + 2 * second));
+    // an answer puts it back to normal
+    liveness.on_heard(now + 2 * second);
+    assert_eq!(liveness.state(), LivenessState::Alive);
+    assert_eq!(liveness.deadline(), Some(now + 3 * second));
+    // the idle timeout ends it for good
+    assert!(liveness.poll(now + 3 * second));
+    assert!(liveness.poll(now + 4 * second));
+    assert!(!liveness.poll(now + 5 * second));
+    assert_eq!(liveness.state(), LivenessState::Dead);
+    liveness.on_heard(now + 5 * second);
+    assert_eq!(liveness.state(), LivenessState::Dead);
+    assert_eq!(liveness.deadline(), None);
+    // turned off it never pings, nor gives up
+    let mut liveness = Liveness::new(None, None, now);
+    assert!(!liveness.poll(now + 1000 * second));
+    assert_eq!(liveness.state(), LivenessState::Alive);
+    assert_eq!(liveness.deadline(), None);
+}
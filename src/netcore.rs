@@ -167,7 +167,7 @@ impl From<IpAddr> for L3Addr {
                 let mut addr: [u16; 8] = [0; 8];
                 let mut index: u8 = 0;
                 for i in &mut addr {
-                    *i = (octets[index as usize] as u16) | (octets[index as usize + 1] as u16);
+                    *i = ((octets[index as usize] as u16) << 8) | (octets[index as usize + 1] as u16);
                     index += 2;
                 }
                 Self::V6(addr)
@@ -208,6 +208,15 @@ pub fn back_and_forth() {
     let newipaddr: IpAddr = L3Addr.into();
     assert_eq!(ipv6addr, newipaddr);
 }
+/// every segment of an ipv6 address must survive the conversion, not just the zero address
+#[test]
+pub fn ipv6_back_and_forth() {
+    let ipv6addr = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1ff, 0xfe23, 0x4567, 0x890a));
+    let newipaddr: IpAddr = L3Addr::from(&ipv6addr).into();
+    assert_eq!(ipv6addr, newipaddr);
+    let socket_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 6432);
+    assert_eq!(SocketAddr::from(L4Addr::from(socket_addr)), socket_addr);
+}
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct L2Addr {
     addr: [u8; 6],
@@ -0,0 +1,142 @@
+//! path mtu discovery along the lines of rfc 8899, every stream sends probes padded to a candidate size
+//! in Admin packets, an acknowledgement shows datagrams that big get through. it is a binary search
+//! between the largest size confirmed and the smallest that got lost
+use std::time::{Duration, Instant};
+
+/// datagram size every stream starts with, ipv6 guarantees 1280 bytes including its own headers
+pub const BASE_MTU: usize = 1200;
+/// largest datagram probed for, the size of a jumbo frame
+pub const MAX_MTU: usize = 9000;
+/// the search stops once the bounds are this close
+const PROBE_STEP: usize = 16;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// a size is given up on after this many probes of it are lost
+const MAX_PROBES: u32 = 3;
+/// how long a finished search is trusted before looking for a larger mtu again
+const RAISE_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+struct Probe {
+    id: u64,
+    size: usize,
+    sent: Instant,
+    attempts: u32,
+}
+
+#[derive(Debug)]
+pub struct Pmtu {
+    mtu: usize,
+    // largest size not known to fail
+    high: usize,
+    probe: Option<Probe>,
+    next_search: Instant,
+    next_id: u64,
+}
+impl Pmtu {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            mtu: BASE_MTU,
+            high: MAX_MTU,
+            probe: None,
+            next_search: now,
+            next_id: 0,
+        }
+    }
+    /// largest datagram known to get through
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+    /// when poll has something to do
+    pub fn deadline(&self) -> Instant {
+        match &self.probe {
+            Some(probe) => probe.sent + PROBE_TIMEOUT,
+            None => self.next_search,
+        }
+    }
+    /// the probe that should be sent now, if any, as its id and size
+    pub fn poll(&mut self, now: Instant) -> Option<(u64, usize)> {
+        if let Some(probe) = self.probe.as_mut() {
+            if now < probe.sent + PROBE_TIMEOUT {
+                return None;
+            }
+            if probe.attempts < MAX_PROBES {
+                probe.attempts += 1;
+                probe.sent = now;
+                return Some((probe.id, probe.size));
+            }
+            self.high = probe.size - 1;
+            self.probe = None;
+        }
+        if now < self.next_search {
+            return None;
+        }
+        if self.high < self.mtu + PROBE_STEP {
+            // the path may take bigger datagrams later on, but the mtu it has now is never lowered
+            self.high = MAX_MTU;
+            self.next_search = now + RAISE_INTERVAL;
+            return None;
+        }
+        self.next_id += 1;
+        let size = (self.mtu + self.high).div_ceil(2);
+        self.probe = Some(Probe {
+            id: self.next_id,
+            size,
+            sent: now,
+            attempts: 1,
+        });
+        Some((self.next_id, size))
+    }
+    /// returns true if the mtu went up
+    pub fn on_ack(&mut self, id: u64, now: Instant) -> bool {
+        match self.probe.take() {
+            Some(probe) if probe.id == id => {
+                self.next_search = now;
+                if probe.size > self.mtu {
+                    self.mtu = probe.size;
+                    return true;
+                }
+                false
+            }
+            probe => {
+                self.probe = probe;
+                false
+            }
+        }
+    }
+    /// the os refused to send the probe, it is bigger than the interface allows
+    pub fn on_too_big(&mut self) {
+        if let Some(probe) = self.probe.take() {
+            self.high = probe.size - 1;
+        }
+    }
+}
+
+#[test]
+fn pmtu_search_test() {
+    // a path that drops anything over 1300 bytes
+    let path_mtu = 1300;
+    let mut now = Instant::now();
+    let mut pmtu = Pmtu::new(now);
+    let mut probes = 0;
+    // until the search is over and the next one is far off
+    while pmtu.deadline() < now + RAISE_INTERVAL / 2 && probes < 100 {
+        now = now.max(pmtu.deadline());
+        if let Some((id, size)) = pmtu.poll(now) {
+            probes += 1;
+            if size <= path_mtu {
+                pmtu.on_ack(id, now);
+            }
+        }
+    }
+    assert!(pmtu.mtu() <= path_mtu && pmtu.mtu() > path_mtu - PROBE_STEP);
+    assert!(probes < 50);
+    // an interface that small makes the os refuse the probe, which counts as lost right away
+    let mut pmtu = Pmtu::new(now);
+    let (_, size) = pmtu.poll(now).unwrap();
+    pmtu.on_too_big();
+    let (_, next) = pmtu.poll(now).unwrap();
+    assert!(next < size);
+    // acknowledgements for other probes don't count
+    assert!(!pmtu.on_ack(1, now));
+    assert_eq!(pmtu.mtu(), BASE_MTU);
+}
@@ -1,11 +1,18 @@
 use crate::encryption::*;
+use crate::liveness::LivenessState;
 use crate::netcore::*;
-use crate::random_string;
+use crate::pmtu::BASE_MTU;
 use crate::NetworkError;
 
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use rand::rngs::OsRng;
+use rand::RngCore;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 //use std::convert::TryFrom;
 
 #[derive(
@@ -21,64 +28,248 @@ use std::net::SocketAddr;
     Debug,
     Clone,
     Copy,
+    Default,
 )]
 pub enum PacketType {
+    #[default]
     RawData = 0,
     RawDataAck = 1,
     Admin = 2,
     AdminAck = 3,
+    /// part of a message too big for one datagram, see fragment::FragmentHeader
+    Fragment = 4,
+    /// data or acknowledgements of a channel of the stream, see channel::ChannelInfo
+    Channel = 5,
+}
+
+/// the aes variant used to encrypt a stream, ordered from weakest to strongest
+#[derive(
+    FromPrimitive,
+    ToPrimitive,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+)]
+pub enum CipherSuite {
+    #[default]
+    Aes128Gcm = 0,
+    Aes192Gcm = 1,
+    Aes256Gcm = 2,
 }
-impl Default for PacketType {
-    fn default() -> Self {
-        Self::RawData
+impl CipherSuite {
+    /// length of the aes key in bytes
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm => 16,
+            Self::Aes192Gcm => 24,
+            Self::Aes256Gcm => 32,
+        }
+    }
+    /// the suite that uses keys of the given length, if there is one
+    pub fn from_key_len(len: usize) -> Option<Self> {
+        match len {
+            16 => Some(Self::Aes128Gcm),
+            24 => Some(Self::Aes192Gcm),
+            32 => Some(Self::Aes256Gcm),
+            _ => None,
+        }
     }
 }
 
+/// tcp port used for the handshake when none is configured
+pub const DEFAULT_HANDSHAKE_PORT: u16 = 6432;
+fn default_handshake_port() -> u16 {
+    DEFAULT_HANDSHAKE_PORT
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RemotePeer {
     addr: L4Addr,
-    pubkey: PubKeyComp
+    #[serde(default = "default_handshake_port")]
+    handshake_port: u16,
+    pubkey: PubKeyComp,
 }
 
 impl RemotePeer {
+    /// the peer is expected to listen for handshakes on DEFAULT_HANDSHAKE_PORT
     pub fn new(addr: L4Addr, pubkey: PubKeyComp) -> Self {
         Self {
             addr,
+            handshake_port: DEFAULT_HANDSHAKE_PORT,
             pubkey,
         }
     }
+    /// for peers that listen for handshakes on a port other than DEFAULT_HANDSHAKE_PORT
+    pub fn with_handshake_port(addr: L4Addr, handshake_port: u16, pubkey: PubKeyComp) -> Self {
+        Self {
+            addr,
+            handshake_port,
+            pubkey,
+        }
+    }
+    /// udp address that stream data is sent to
     pub fn socket_addr(&self) -> SocketAddr {
         self.addr.into()
     }
+    /// tcp address the handshake is made with, it shares the ip of socket_addr
+    pub fn handshake_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.ip().into(), self.handshake_port)
+    }
+    pub fn handshake_port(&self) -> u16 {
+        self.handshake_port
+    }
+    /// the long term public key the peer must prove it holds during the handshake
+    pub fn pubkey(&self) -> &PubKeyComp {
+        &self.pubkey
+    }
     pub fn decompose(self) -> (L4Addr, PubKeyComp) {
         (self.addr, self.pubkey)
     }
 }
 
-use std::convert::TryInto;
+/// first message of the handshake, sent by the peer opening the stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientHello {
+    peer: RemotePeer,
+    cipher_suite: CipherSuite,
+    ephemeral_key: [u8; 32],
+    #[serde(default)]
+    delivery_mode: DeliveryMode,
+    connection_id: ConnectionId,
+}
+impl ClientHello {
+    pub fn new(
+        peer: RemotePeer,
+        cipher_suite: CipherSuite,
+        ephemeral_key: [u8; 32],
+        delivery_mode: DeliveryMode,
+        connection_id: ConnectionId,
+    ) -> Self {
+        Self {
+            peer,
+            cipher_suite,
+            ephemeral_key,
+            delivery_mode,
+            connection_id,
+        }
+    }
+    /// the id the client receives the datagrams of the stream on
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+    /// both ends of the stream use the mode the client asked for
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+    }
+    /// address and long term public key of the client
+    pub fn peer(&self) -> &RemotePeer {
+        &self.peer
+    }
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
+    }
+    pub fn ephemeral_key(&self) -> [u8; 32] {
+        self.ephemeral_key
+    }
+}
+/// reply to ClientHello, it is followed by the servers signature over both messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerHello {
+    pubkey: PubKeyComp,
+    ephemeral_key: [u8; 32],
+    connection_id: ConnectionId,
+}
+impl ServerHello {
+    pub fn new(pubkey: PubKeyComp, ephemeral_key: [u8; 32], connection_id: ConnectionId) -> Self {
+        Self {
+            pubkey,
+            ephemeral_key,
+            connection_id,
+        }
+    }
+    /// the id the server receives the datagrams of the stream on
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+    /// long term public key of the server, used to check its transcript signature
+    pub fn pubkey(&self) -> &PubKeyComp {
+        &self.pubkey
+    }
+    pub fn ephemeral_key(&self) -> [u8; 32] {
+        self.ephemeral_key
+    }
+}
+
+/// length of the connection id every stream datagram starts with
+pub const CID_LEN: usize = 8;
+
+/// each end of a stream picks one for the datagrams it receives, and the peer puts it in front of every
+/// datagram, unencrypted. the socket finds the stream by it rather than by address, so the stream still
+/// gets its datagrams once the address of the peer changes
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct ConnectionId {
+    value: u64,
+}
+impl ConnectionId {
+    pub fn new(value: u64) -> Self {
+        Self { value }
+    }
+    pub fn random() -> Self {
+        Self::new(OsRng.next_u64())
+    }
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+    pub fn to_bytes(&self) -> [u8; CID_LEN] {
+        self.value.to_be_bytes()
+    }
+    /// the id a datagram is for, None if it is too short to have one
+    pub fn from_datagram(data: &[u8]) -> Option<Self> {
+        let bytes = data.get(0..CID_LEN)?;
+        Some(Self::new(u64::from_be_bytes(bytes.try_into().ok()?)))
+    }
+}
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Hash)]
+/// length of the raw StreamHeader, a packet type and a seq
+pub const STREAM_HEADER_LEN: usize = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StreamHeader {
-    /// this exists for legacy reasons
-    checksum: [u8; 32],
-    aes_key: Vec<u8>,
+    cipher_suite: CipherSuite,
+    aes_key: SessionKey,
     packet_len: usize,
     packet_type: PacketType,
-    remander: u8,
+    /// packet number, it goes out in the clear but is authenticated with the payload so it can't be altered
+    seq: u64,
 }
 impl StreamHeader {
     pub fn new(packet_len: usize) -> Self {
-        let aes_key: Vec<u8> = random_string(16).into_bytes();
-        Self::with_key(aes_key, packet_len)
+        Self::with_suite(CipherSuite::default(), packet_len)
+    }
+    /// generates a random key of the length used by the cipher suite
+    pub fn with_suite(cipher_suite: CipherSuite, packet_len: usize) -> Self {
+        Self::with_key(SessionKey::generate(cipher_suite), packet_len)
     }
-    pub fn with_key(aes_key: Vec<u8>, packet_len: usize) -> Self {
-        let checksum = [0; 32];
+    /// the cipher suite is picked based on the key length, which must be 16, 24, or 32 bytes
+    pub fn with_key(aes_key: SessionKey, packet_len: usize) -> Self {
+        let cipher_suite =
+            CipherSuite::from_key_len(aes_key.len()).expect("aes key must be 16, 24, or 32 bytes");
         Self {
-            checksum,
+            cipher_suite,
             aes_key,
             packet_len,
             packet_type: PacketType::RawData,
-            remander: 0,
+            seq: 0,
         }
     }
     pub fn set_packet_type(&mut self, packet_type: PacketType) {
@@ -87,8 +278,11 @@ impl StreamHeader {
     pub fn packet_type(&self) -> PacketType {
         self.packet_type
     }
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
+    }
     pub fn key(&self) -> &[u8] {
-        &self.aes_key
+        self.aes_key.as_bytes()
     }
     pub fn packet_len(&self) -> usize {
         self.packet_len
@@ -99,51 +293,548 @@ impl StreamHeader {
     pub fn set_packet_len(&mut self, packet_len: usize) {
         self.packet_len = packet_len;
     }
-    /// remander is calculated by 128 - (packet_len % 128) to break into encryptable blocks for async
-    /// for sync calculated based on 16 - (packet_len % 128)
-    pub fn remander(&self) -> u8 {
-        self.remander
+    /// 0 for packets that aren't part of a stream, such as the handshake confirmation
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
     }
-    pub fn set_remander(&mut self, remander: u8) {
-        self.remander = remander;
+    /// length of the output of to_raw
+    pub fn raw_len(&self) -> usize {
+        STREAM_HEADER_LEN
     }
-    /// used in place of serde_json::to_string(), because serde_json generates un-needed data
+    /// the part of the header that goes on the wire, the packet type and seq.
+    /// the key never leaves the host, and the length is covered by the tag
     pub fn to_raw(&self) -> Vec<u8> {
-        let mut outvec: Vec<u8> = Vec::with_capacity(58);
-        outvec.extend_from_slice(&self.checksum);
-        outvec.extend_from_slice(&self.aes_key);
-        outvec.extend_from_slice(&self.packet_len.to_be_bytes());
-        outvec.push(self.remander);
+        let mut outvec: Vec<u8> = Vec::with_capacity(self.raw_len());
         outvec.push(self.packet_type.to_u8().unwrap_or_default());
+        outvec.extend_from_slice(&self.seq.to_be_bytes());
         outvec
     }
-    pub fn to_raw_padded(&self) -> Vec<u8> {
-        let mut vec = self.to_raw();
-        let mut rem_vec = Vec::with_capacity(71);
-        unsafe { rem_vec.set_len(71) };
-        vec.extend_from_slice(&rem_vec);
-        vec
+    /// the header of a packet that arrived under this one, with the packet type and seq it carried
+    pub fn with_raw(&self, data: &[u8]) -> Result<Self, NetworkError> {
+        if data.len() != STREAM_HEADER_LEN {
+            return Err(NetworkError::ConnectionDenied(
+                "invalid stream header length".to_string(),
+            ));
+        }
+        let mut header = self.clone();
+        header.packet_type = FromPrimitive::from_u8(data[0]).unwrap_or_default();
+        header.seq = u64::from_be_bytes(data[1..9].try_into()?);
+        Ok(header)
     }
-    pub fn from_raw_padded(data: &[u8]) -> Result<Self, NetworkError> {
-        Self::from_raw(&data[0..58])
+}
+
+/// hands out the sequence numbers of a stream, clones share the count so split senders never reuse one
+#[derive(Debug, Clone, Default)]
+pub struct SeqCounter {
+    next: Arc<AtomicU64>,
+}
+impl SeqCounter {
+    /// numbering starts at 1
+    pub fn next(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed) + 1
     }
-    /// convert 125 bytes (length of data) to StreamHeader
-    pub fn from_raw(data: &[u8]) -> Result<Self, NetworkError> {
-        assert_eq!(data.len(), 58);
-        let checksum = data[0..32].try_into().unwrap();
-        let aes_key = data[32..48].to_vec();
-        let packet_len = usize::from_be_bytes(data[48..56].try_into()?);
-        let remander = data[56];
-        let packet_type = FromPrimitive::from_u8(data[57]).unwrap_or_default();
-        Ok(Self {
-            checksum,
-            aes_key,
-            packet_len,
-            remander,
+}
+
+/// number of sequence numbers behind the highest seen that ReplayWindow still accepts
+pub const REPLAY_WINDOW_LEN: u64 = 64;
+
+/// anti-replay window, in the style of ipsec, each sequence number is accepted once,
+/// and only if it's no more than REPLAY_WINDOW_LEN behind the highest accepted so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayWindow {
+    highest: u64,
+    // bit n is set if highest - n has been accepted
+    bitmap: u64,
+}
+impl ReplayWindow {
+    /// the newest sequence number accepted so far
+    pub fn highest(&self) -> u64 {
+        self.highest
+    }
+    /// returns false if seq was already seen or is too old to tell
+    pub fn accept(&mut self, seq: u64) -> bool {
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.bitmap = if shift >= REPLAY_WINDOW_LEN {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.highest = seq;
+            return true;
+        }
+        let offset = self.highest - seq;
+        // 0 is never sent by a stream
+        if seq == 0 || offset >= REPLAY_WINDOW_LEN || self.bitmap & (1 << offset) != 0 {
+            return false;
+        }
+        self.bitmap |= 1 << offset;
+        true
+    }
+}
+
+/// counters kept for a stream, shared by the stream, its split halves, and any task working for it
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    replays: AtomicU64,
+    forgeries: AtomicU64,
+    retransmits: AtomicU64,
+    stale: AtomicU64,
+    incomplete: AtomicU64,
+    rekeys: AtomicU64,
+    migrations: AtomicU64,
+    // microseconds, 0 until measured
+    rtt: AtomicU64,
+    // 0 until discovered
+    mtu: AtomicUsize,
+    // a LivenessState, in the order it is declared
+    liveness: AtomicU8,
+}
+impl StreamStats {
+    /// packets dropped because they had already been received
+    pub fn replays(&self) -> u64 {
+        self.replays.load(Ordering::Relaxed)
+    }
+    /// packets dropped because they didn't decrypt, they were forged or damaged on the way
+    pub fn forgeries(&self) -> u64 {
+        self.forgeries.load(Ordering::Relaxed)
+    }
+    /// packets sent again because they weren't acknowledged in time, only for reliable streams
+    pub fn retransmits(&self) -> u64 {
+        self.retransmits.load(Ordering::Relaxed)
+    }
+    /// packets dropped because a newer one had already been delivered, only for latest only streams.
+    /// those too old for the replay window are counted as replays instead
+    pub fn stale(&self) -> u64 {
+        self.stale.load(Ordering::Relaxed)
+    }
+    /// fragmented messages dropped because they weren't complete in time, or didn't fit in memory
+    pub fn incomplete(&self) -> u64 {
+        self.incomplete.load(Ordering::Relaxed)
+    }
+    /// times this end moved on to a new key, see RekeyPolicy
+    pub fn rekeys(&self) -> u64 {
+        self.rekeys.load(Ordering::Relaxed)
+    }
+    /// times the peer was found at a new address, and the stream moved there
+    pub fn migrations(&self) -> u64 {
+        self.migrations.load(Ordering::Relaxed)
+    }
+    /// smoothed round trip time, only measured on reliable streams
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+    /// largest datagram known to reach the peer, see pmtu
+    pub fn mtu(&self) -> usize {
+        match self.mtu.load(Ordering::Relaxed) {
+            0 => BASE_MTU,
+            mtu => mtu,
+        }
+    }
+    /// whether the peer has been heard from lately, see liveness
+    pub fn liveness(&self) -> LivenessState {
+        match self.liveness.load(Ordering::Relaxed) {
+            0 => LivenessState::Alive,
+            1 => LivenessState::Probing,
+            _ => LivenessState::Dead,
+        }
+    }
+    pub fn set_liveness(&self, state: LivenessState) {
+        let state = match state {
+            LivenessState::Alive => 0,
+            LivenessState::Probing => 1,
+            LivenessState::Dead => 2,
+        };
+        self.liveness.store(state, Ordering::Relaxed);
+    }
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+    pub fn set_rtt(&self, rtt: Duration) {
+        self.rtt
+            .store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+    }
+    pub fn add_replay(&self) {
+        self.replays.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn add_forgery(&self) {
+        self.forgeries.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn add_incomplete(&self) {
+        self.incomplete.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn add_stale(&self) {
+        self.stale.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn add_migration(&self) {
+        self.migrations.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn add_rekey(&self) {
+        self.rekeys.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn add_retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// what a stream guarantees about the messages it delivers, chosen by the side that connects
+#[derive(
+    Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Default,
+)]
+pub enum DeliveryMode {
+    /// plain datagrams, messages may be lost or arrive out of order
+    #[default]
+    Unordered,
+    /// every message arrives once and in order, lost packets are retransmitted
+    Reliable,
+    /// only packets newer than the last one delivered, anything older is dropped rather than delivered late
+    LatestOnly,
+}
+
+/// one message from the peer, whole, with what the packet that brought it said about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    data: Vec<u8>,
+    packet_type: PacketType,
+    seq: u64,
+    arrived: Instant,
+}
+impl Message {
+    pub fn new(data: Vec<u8>, packet_type: PacketType, seq: u64, arrived: Instant) -> Self {
+        Self {
+            data,
             packet_type,
-        })
+            seq,
+            arrived,
+        }
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+    pub fn packet_type(&self) -> PacketType {
+        self.packet_type
+    }
+    /// sequence number of the packet, the last one for a message sent in fragments
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+    /// when the session received it, a reliable message may have waited for earlier ones after that
+    pub fn arrived(&self) -> Instant {
+        self.arrived
+    }
+}
+
+/// how a socket makes the handshake for new streams
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy, Default)]
+pub enum HandshakeMode {
+    /// over a tcp connection to the peers handshake address
+    #[default]
+    Tcp,
+    /// over the udp socket stream data is sent on, so only one udp port has to be reachable
+    Udp,
+}
+
+/// every udp handshake datagram starts with this, so they can be told apart from stream data
+pub const HANDSHAKE_MAGIC: [u8; 8] = *b"SLLP-HS1";
+
+/// the messages of the udp handshake, the hellos and signatures are the same as those of the tcp handshake.
+/// every one of them carries the connection id of the client, so several handshakes with the same peer
+/// can run at once
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeMsg {
+    /// the cookie is empty on the first attempt
+    ClientHello { cookie: Vec<u8>, hello: Vec<u8> },
+    /// the server won't do any work until the client proves it can receive at its address
+    Retry { cookie: Vec<u8> },
+    ServerHello { hello: Vec<u8>, signature: Vec<u8> },
+    Finish { signature: Vec<u8> },
+    /// encrypted with the session key, so the client knows both sides derived the same one
+    Confirm { data: Vec<u8> },
+}
+impl HandshakeMsg {
+    /// true if the datagram should be handled as part of a handshake rather than stream data
+    pub fn is_handshake(data: &[u8]) -> bool {
+        data.len() > HANDSHAKE_MAGIC.len() && data[0..HANDSHAKE_MAGIC.len()] == HANDSHAKE_MAGIC
+    }
+    /// the handshake a datagram belongs to, without parsing the rest of it
+    pub fn connection_id(data: &[u8]) -> Option<ConnectionId> {
+        ConnectionId::from_datagram(data.get(HANDSHAKE_MAGIC.len()..)?)
+    }
+    /// id is the connection id of the client
+    pub fn to_raw(&self, id: ConnectionId) -> Vec<u8> {
+        let (tag, first, rest): (u8, &[u8], &[u8]) = match self {
+            Self::ClientHello { cookie, hello } => (1, cookie, hello),
+            Self::Retry { cookie } => (2, &[], cookie),
+            Self::ServerHello { hello, signature } => (3, hello, signature),
+            Self::Finish { signature } => (4, &[], signature),
+            Self::Confirm { data } => (5, &[], data),
+        };
+        let mut outvec =
+            Vec::with_capacity(HANDSHAKE_MAGIC.len() + CID_LEN + 3 + first.len() + rest.len());
+        outvec.extend_from_slice(&HANDSHAKE_MAGIC);
+        outvec.extend_from_slice(&id.to_bytes());
+        outvec.push(tag);
+        outvec.extend_from_slice(&(first.len() as u16).to_be_bytes());
+        outvec.extend_from_slice(first);
+        outvec.extend_from_slice(rest);
+        outvec
+    }
+    pub fn from_raw(data: &[u8]) -> Result<(ConnectionId, Self), NetworkError> {
+        let invalid = || NetworkError::ConnectionDenied("invalid handshake message".to_string());
+        let start = HANDSHAKE_MAGIC.len() + CID_LEN + 3;
+        let id = match Self::connection_id(data) {
+            Some(id) if data.len() >= start && Self::is_handshake(data) => id,
+            _ => return Err(invalid()),
+        };
+        let first_len = u16::from_be_bytes(data[start - 2..start].try_into()?) as usize;
+        if data.len() < start + first_len {
+            return Err(invalid());
+        }
+        let first = data[start..start + first_len].to_vec();
+        let rest = data[start + first_len..].to_vec();
+        let message = match data[start - 3] {
+            1 => Self::ClientHello {
+                cookie: first,
+                hello: rest,
+            },
+            2 => Self::Retry { cookie: rest },
+            3 => Self::ServerHello {
+                hello: first,
+                signature: rest,
+            },
+            4 => Self::Finish { signature: rest },
+            5 => Self::Confirm { data: rest },
+            _ => return Err(invalid()),
+        };
+        Ok((id, message))
+    }
+}
+
+#[test]
+fn handshake_msg_raw_test() {
+    let messages = vec![
+        HandshakeMsg::ClientHello {
+            cookie: Vec::new(),
+            hello: b"hello".to_vec(),
+        },
+        HandshakeMsg::ClientHello {
+            cookie: vec![7; 40],
+            hello: b"hello".to_vec(),
+        },
+        HandshakeMsg::Retry { cookie: vec![7; 40] },
+        HandshakeMsg::ServerHello {
+            hello: b"hello".to_vec(),
+            signature: vec![1; 256],
+        },
+        HandshakeMsg::Finish {
+            signature: vec![2; 256],
+        },
+        HandshakeMsg::Confirm { data: vec![3; 64] },
+    ];
+    let id = ConnectionId::new(42);
+    for message in messages {
+        let raw = message.to_raw(id);
+        assert!(HandshakeMsg::is_handshake(&raw));
+        assert_eq!(HandshakeMsg::connection_id(&raw), Some(id));
+        assert_eq!(HandshakeMsg::from_raw(&raw).unwrap(), (id, message));
+    }
+    assert!(HandshakeMsg::from_raw(b"SLLP-HS1\0\0\0\0\0\0\0\x2a\x09\x00\x00").is_err());
+    assert!(HandshakeMsg::from_raw(b"SLLP-HS1\0\0\0\0\0\0\0\x2a\x01\xff\xff").is_err());
+    assert!(HandshakeMsg::from_raw(b"SLLP-HS1\x01\x00\x00").is_err());
+    assert!(!HandshakeMsg::is_handshake(b"stream data"));
+}
+
+#[test]
+fn replay_window_test() {
+    let mut window = ReplayWindow::default();
+    assert!(!window.accept(0));
+    assert!(window.accept(1));
+    assert!(!window.accept(1));
+    // out of order, but inside the window
+    assert!(window.accept(5));
+    assert!(window.accept(3));
+    assert!(!window.accept(3));
+    assert!(window.accept(2));
+    assert!(window.accept(5 + REPLAY_WINDOW_LEN - 1));
+    // 5 is now at the far edge of the window, 4 just past it
+    assert!(!window.accept(5));
+    assert!(!window.accept(4));
+    // a jump wider than the window forgets everything before it
+    assert!(window.accept(1000));
+    assert!(!window.accept(1000 - REPLAY_WINDOW_LEN));
+    assert!(window.accept(1000 - REPLAY_WINDOW_LEN + 1));
+    assert_eq!(window.highest, 1000);
+
+    let counter = SeqCounter::default();
+    let clone = counter.clone();
+    assert_eq!(counter.next(), 1);
+    assert_eq!(clone.next(), 2);
+}
+
+/// payload of a RawDataAck packet, acknowledges every message below cumulative,
+/// and the message ranges in blocks, each from start up to but not including end
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SelectiveAck {
+    cumulative: u64,
+    blocks: Vec<(u64, u64)>,
+}
+impl SelectiveAck {
+    pub fn new(cumulative: u64, blocks: Vec<(u64, u64)>) -> Self {
+        Self { cumulative, blocks }
+    }
+    /// ranges received after a gap, in ascending order
+    pub fn blocks(&self) -> &[(u64, u64)] {
+        &self.blocks
+    }
+    pub fn acks(&self, msg: u64) -> bool {
+        msg < self.cumulative
+            || self
+                .blocks
+                .iter()
+                .any(|(start, end)| msg >= *start && msg < *end)
+    }
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut outvec = Vec::with_capacity(10 + self.blocks.len() * 16);
+        outvec.extend_from_slice(&self.cumulative.to_be_bytes());
+        outvec.extend_from_slice(&(self.blocks.len() as u16).to_be_bytes());
+        for (start, end) in self.blocks.iter() {
+            outvec.extend_from_slice(&start.to_be_bytes());
+            outvec.extend_from_slice(&end.to_be_bytes());
+        }
+        outvec
+    }
+    pub fn from_raw(data: &[u8]) -> Result<Self, NetworkError> {
+        let invalid = || NetworkError::ConnectionDenied("invalid acknowledgement".to_string());
+        if data.len() < 10 {
+            return Err(invalid());
+        }
+        let cumulative = u64::from_be_bytes(data[0..8].try_into()?);
+        let count = u16::from_be_bytes(data[8..10].try_into()?) as usize;
+        if data.len() != 10 + count * 16 {
+            return Err(invalid());
+        }
+        let blocks = data[10..]
+            .chunks(16)
+            .map(|block| {
+                (
+                    u64::from_be_bytes(block[0..8].try_into().unwrap()),
+                    u64::from_be_bytes(block[8..16].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Ok(Self { cumulative, blocks })
+    }
+}
+
+#[test]
+fn selective_ack_raw_test() {
+    let ack = SelectiveAck::new(5, vec![(7, 9), (12, 13)]);
+    assert_eq!(SelectiveAck::from_raw(&ack.to_raw()).unwrap(), ack);
+    let acked: Vec<u64> = (0..15).filter(|msg| ack.acks(*msg)).collect();
+    assert_eq!(acked, vec![0, 1, 2, 3, 4, 7, 8, 12]);
+    assert!(SelectiveAck::from_raw(&ack.to_raw()[0..20]).is_err());
+}
+
+/// payload of Admin packets, and of the AdminAck packets that answer them, the first byte says which message it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminMsg {
+    /// sent padded out to size bytes, answered with a ProbeAck if it gets through
+    Probe { id: u64, size: u32 },
+    ProbeAck { id: u64, size: u32 },
+    /// the sender is done with the stream, sent once everything before it has been acknowledged
+    Close,
+    CloseAck,
+    /// sent when the peer has gone quiet, any answer shows it is still there
+    Ping,
+    Pong,
+    /// sent to a new address the peer seems to be at, the stream only moves there once it is echoed back
+    PathChallenge { token: u64 },
+    PathResponse { token: u64 },
+}
+impl AdminMsg {
+    pub fn to_raw(&self) -> Vec<u8> {
+        let (kind, id, size) = match self {
+            Self::Probe { id, size } => (0, id, size),
+            Self::ProbeAck { id, size } => (1, id, size),
+            Self::Close => return vec![2],
+            Self::CloseAck => return vec![3],
+            Self::Ping => return vec![4],
+            Self::Pong => return vec![5],
+            Self::PathChallenge { token } => return [&[6], &token.to_be_bytes()[..]].concat(),
+            Self::PathResponse { token } => return [&[7], &token.to_be_bytes()[..]].concat(),
+        };
+        let mut outvec = vec![kind];
+        outvec.extend_from_slice(&id.to_be_bytes());
+        outvec.extend_from_slice(&size.to_be_bytes());
+        outvec
+    }
+    /// anything after the message, such as the padding of a probe, is ignored
+    pub fn from_raw(data: &[u8]) -> Result<Self, NetworkError> {
+        let invalid = || NetworkError::ConnectionDenied("invalid admin message".to_string());
+        match data.first() {
+            Some(kind @ 0..=1) if data.len() >= 13 => {
+                let id = u64::from_be_bytes(data[1..9].try_into()?);
+                let size = u32::from_be_bytes(data[9..13].try_into()?);
+                Ok(if *kind == 0 {
+                    Self::Probe { id, size }
+                } else {
+                    Self::ProbeAck { id, size }
+                })
+            }
+            Some(2) => Ok(Self::Close),
+            Some(3) => Ok(Self::CloseAck),
+            Some(4) => Ok(Self::Ping),
+            Some(5) => Ok(Self::Pong),
+            Some(kind @ 6..=7) if data.len() >= 9 => {
+                let token = u64::from_be_bytes(data[1..9].try_into()?);
+                Ok(if *kind == 6 {
+                    Self::PathChallenge { token }
+                } else {
+                    Self::PathResponse { token }
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[test]
+fn admin_msg_raw_test() {
+    for msg in &[
+        AdminMsg::Probe { id: 3, size: 1400 },
+        AdminMsg::ProbeAck { id: 3, size: 1400 },
+    ] {
+        let mut raw = msg.to_raw();
+        assert_eq!(&AdminMsg::from_raw(&raw).unwrap(), msg);
+        raw.extend_from_slice(&[0; 100]);
+        assert_eq!(&AdminMsg::from_raw(&raw).unwrap(), msg);
+        assert!(AdminMsg::from_raw(&raw[0..12]).is_err());
+    }
+    for msg in &[
+        AdminMsg::Close,
+        AdminMsg::CloseAck,
+        AdminMsg::Ping,
+        AdminMsg::Pong,
+    ] {
+        assert_eq!(&AdminMsg::from_raw(&msg.to_raw()).unwrap(), msg);
     }
-    pub fn checksum(&self) -> &[u8] {
-        &self.checksum
+    for msg in &[
+        AdminMsg::PathChallenge { token: 7 },
+        AdminMsg::PathResponse { token: 7 },
+    ] {
+        let raw = msg.to_raw();
+        assert_eq!(&AdminMsg::from_raw(&raw).unwrap(), msg);
+        assert!(AdminMsg::from_raw(&raw[0..8]).is_err());
     }
+    assert!(AdminMsg::from_raw(&[9; 13]).is_err());
 }
@@ -0,0 +1,297 @@
+//! token bucket rate limits, on what a stream sends, what every stream of a socket sends together,
+//! and on what the socket accepts from each source address
+use crate::NetworkError;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// sources the socket keeps a limiter for, the ones quiet longest are forgotten past this
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// a rate in bytes and packets per second, None leaves it unlimited.
+/// up to a second's worth can be used at once after a quiet spell
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct RateLimit {
+    #[serde(default)]
+    bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    packets_per_sec: Option<u64>,
+}
+impl RateLimit {
+    pub fn new(bytes_per_sec: Option<u64>, packets_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec,
+            packets_per_sec,
+        }
+    }
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.bytes_per_sec
+    }
+    pub fn packets_per_sec(&self) -> Option<u64> {
+        self.packets_per_sec
+    }
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes_per_sec.is_none() && self.packets_per_sec.is_none()
+    }
+}
+
+/// fills up at rate tokens per second, holding at most a second's worth
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+impl TokenBucket {
+    /// starts out full
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+    /// a full bucket lets anything through, going into debt for what is more than a second's worth,
+    /// so a message bigger than the rate isn't refused forever
+    pub fn allows(&mut self, amount: usize, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= (amount as f64).min(self.rate)
+    }
+    pub fn take(&mut self, amount: usize) {
+        self.tokens -= amount as f64;
+    }
+}
+
+/// the byte and packet buckets of a RateLimit
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+}
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimit::unlimited(), Instant::now())
+    }
+}
+impl RateLimiter {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            bytes: limit.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            packets: limit
+                .packets_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+    pub fn allows(&mut self, bytes: usize, packets: usize, now: Instant) -> bool {
+        let bytes_ok = match self.bytes.as_mut() {
+            Some(bucket) => bucket.allows(bytes, now),
+            None => true,
+        };
+        let packets_ok = match self.packets.as_mut() {
+            Some(bucket) => bucket.allows(packets, now),
+            None => true,
+        };
+        bytes_ok && packets_ok
+    }
+    pub fn take(&mut self, bytes: usize, packets: usize) {
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.take(bytes);
+        }
+        if let Some(bucket) = self.packets.as_mut() {
+            bucket.take(packets);
+        }
+    }
+    /// takes them only if both buckets allow it
+    pub fn try_take(&mut self, bytes: usize, packets: usize, now: Instant) -> bool {
+        if !self.allows(bytes, packets, now) {
+            return false;
+        }
+        self.take(bytes, packets);
+        true
+    }
+}
+
+/// a RateLimiter that can be shared, such as by every stream of a socket
+#[derive(Debug, Clone, Default)]
+pub struct SharedLimiter {
+    value: Arc<Mutex<RateLimiter>>,
+}
+impl SharedLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            value: Arc::new(Mutex::new(RateLimiter::new(limit, Instant::now()))),
+        }
+    }
+    pub fn lock(&self) -> std::sync::MutexGuard<'_, RateLimiter> {
+        // nothing panics while holding the lock, but a poisoned limiter is still a valid one
+        self.value.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// the limits a stream checks before sending, its own and that of its socket.
+/// the halves of a split stream share them
+#[derive(Debug, Clone, Default)]
+pub struct SendLimits {
+    stream: SharedLimiter,
+    socket: SharedLimiter,
+}
+impl SendLimits {
+    pub fn new(stream: RateLimit, socket: SharedLimiter) -> Self {
+        Self {
+            stream: SharedLimiter::new(stream),
+            socket,
+        }
+    }
+    /// a message of bytes taking up packets datagrams, it is only counted against either limit if it is within both
+    pub fn check(&self, bytes: usize, packets: usize) -> Result<(), NetworkError> {
+        let now = Instant::now();
+        let mut stream = self.stream.lock();
+        let mut socket = self.socket.lock();
+        if !stream.allows(bytes, packets, now) {
+            return Err(NetworkError::RateLimited(format!(
+                "stream send limit of {:?}",
+                stream.limit()
+            )));
+        }
+        if !socket.allows(bytes, packets, now) {
+            return Err(NetworkError::RateLimited(format!(
+                "socket send limit of {:?}",
+                socket.limit()
+            )));
+        }
+        stream.take(bytes, packets);
+        socket.take(bytes, packets);
+        Ok(())
+    }
+}
+
+/// what became of a datagram checked against the limit of its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Accepted,
+    /// dropped, and the first to be since the source was last within its limit
+    Limited,
+    Dropped,
+}
+
+/// a limiter for every address datagrams arrive from
+#[derive(Debug)]
+pub struct PeerLimiters {
+    limit: RateLimit,
+    // and whether the source was over the limit the last time, and when it was last heard from
+    limiters: HashMap<SocketAddr, (RateLimiter, bool, u64)>,
+    // the sources by when they were last heard from, so the one quiet longest is forgotten first
+    by_use: BTreeMap<u64, SocketAddr>,
+    uses: u64,
+}
+impl PeerLimiters {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            limiters: HashMap::new(),
+            by_use: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+    pub fn admit(&mut self, addr: SocketAddr, bytes: usize, now: Instant) -> Admission {
+        if self.limit.is_unlimited() {
+            return Admission::Accepted;
+        }
+        // a source being held to its limit keeps being heard from, so it can't get out of it
+        // by spoofing new ones, those push out the sources that went quiet
+        if !self.limiters.contains_key(&addr) && self.limiters.len() >= MAX_TRACKED_SOURCES {
+            if let Some((_, quietest)) = self.by_use.pop_first() {
+                self.limiters.remove(&quietest);
+            }
+        }
+        let limit = self.limit;
+        self.uses += 1;
+        let (limiter, limited, used) = self
+            .limiters
+            .entry(addr)
+            .or_insert_with(|| (RateLimiter::new(limit, now), false, 0));
+        self.by_use.remove(used);
+        *used = self.uses;
+        self.by_use.insert(self.uses, addr);
+        if limiter.try_take(bytes, 1, now) {
+            *limited = false;
+            Admission::Accepted
+        } else if !*limited {
+            *limited = true;
+            Admission::Limited
+        } else {
+            Admission::Dropped
+        }
+    }
+}
+
+#[test]
+fn token_bucket_test() {
+    use std::time::Duration;
+    let now = Instant::now();
+    let mut limiter = RateLimiter::new(RateLimit::new(Some(1000), Some(10)), now);
+    // a second's worth at once, then nothing more
+    assert!(limiter.try_take(600, 1, now));
+    assert!(limiter.try_take(400, 1, now));
+    assert!(!limiter.try_take(1, 1, now));
+    // it refills with time
+    let later = now + Duration::from_millis(500);
+    assert!(limiter.try_take(500, 1, later));
+    assert!(!limiter.try_take(100, 1, later));
+    // packets run out before bytes do
+    let mut limiter = RateLimiter::new(RateLimit::new(None, Some(2)), now);
+    assert!(limiter.try_take(10_000, 1, now));
+    assert!(limiter.try_take(10_000, 1, now));
+    assert!(!limiter.try_take(1, 1, now));
+    // more than a second's worth gets through a full bucket
+    let mut limiter = RateLimiter::new(RateLimit::new(Some(100), None), now);
+    assert!(limiter.try_take(1000, 1, now));
+    assert!(!limiter.try_take(1, 1, now + Duration::from_secs(5)));
+    assert!(limiter.try_take(1, 1, now + Duration::from_secs(10)));
+}
+#[test]
+fn peer_limiters_test() {
+    let now = Instant::now();
+    let mut limiters = PeerLimiters::new(RateLimit::new(None, Some(2)));
+    let noisy = SocketAddr::from(([127, 0, 0, 1], 1000));
+    let quiet = SocketAddr::from(([127, 0, 0, 1], 1001));
+    assert_eq!(limiters.admit(noisy, 100, now), Admission::Accepted);
+    assert_eq!(limiters.admit(noisy, 100, now), Admission::Accepted);
+    assert_eq!(limiters.admit(noisy, 100, now), Admission::Limited);
+    assert_eq!(limiters.admit(noisy, 100, now), Admission::Dropped);
+    // every source has a limit of its own
+    assert_eq!(limiters.admit(quiet, 100, now), Admission::Accepted);
+    // once there are too many, new sources take the place of the ones quiet longest,
+    // while one that keeps sending stays held to its limit
+    for port in 2000..2000 + MAX_TRACKED_SOURCES as u16 {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        assert_eq!(limiters.admit(addr, 100, now), Admission::Accepted);
+        assert_eq!(limiters.admit(noisy, 100, now), Admission::Dropped);
+    }
+    assert_eq!(limiters.limiters.len(), MAX_TRACKED_SOURCES);
+    assert_eq!(limiters.by_use.len(), MAX_TRACKED_SOURCES);
+    assert!(!limiters.limiters.contains_key(&quiet));
+    let mut unlimited = PeerLimiters::new(RateLimit::unlimited());
+    for _ in 0..100 {
+        assert_eq!(unlimited.admit(noisy, 100, now), Admission::Accepted);
+    }
+}
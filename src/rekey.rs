@@ -0,0 +1,223 @@
+//! rekeying, a stream moves on to a new key once it has sent enough under the current one.
+//! every key is derived from the one before it, so nothing is exchanged and the peer just tries the next
+//! few keys when the current one fails. the keys before stay around for a while, for packets still on the way
+use crate::encryption::{next_key, sym_aes_decrypt};
+use crate::protocol::{StreamHeader, StreamStats};
+use crate::NetworkError;
+use std::time::{Duration, Instant};
+
+/// how long a replaced key still decrypts what arrives under it
+pub const KEY_OVERLAP: Duration = Duration::from_secs(10);
+/// how many keys past the current one a packet is tried under, the peer can rekey more than once
+/// before anything it sent under the key in between makes it here
+pub const MAX_KEYS_AHEAD: usize = 4;
+
+/// when a stream changes its key, whichever limit is reached first, None for one leaves it out.
+/// with random nonces a gcm key is good for about 2^32 packets, the defaults stay far below that
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(default)]
+pub struct RekeyPolicy {
+    bytes: Option<u64>,
+    packets: Option<u64>,
+    interval: Option<Duration>,
+}
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            bytes: Some(1 << 30),
+            packets: Some(1 << 24),
+            interval: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+impl RekeyPolicy {
+    pub fn new(bytes: Option<u64>, packets: Option<u64>, interval: Option<Duration>) -> Self {
+        Self {
+            bytes,
+            packets,
+            interval,
+        }
+    }
+    /// keeps the key of the handshake for the life of the stream
+    pub fn never() -> Self {
+        Self::new(None, None, None)
+    }
+    pub fn bytes(&self) -> Option<u64> {
+        self.bytes
+    }
+    pub fn packets(&self) -> Option<u64> {
+        self.packets
+    }
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+}
+
+/// the keys of a stream, the one it sends with and the ones it accepts.
+/// each direction moves on by itself, as the end sending in it decides
+#[derive(Debug)]
+pub struct KeySchedule {
+    policy: RekeyPolicy,
+    send: StreamHeader,
+    sent_bytes: u64,
+    sent_packets: u64,
+    send_since: Instant,
+    recv: StreamHeader,
+    // the keys the peer moves on to next, in order
+    recv_ahead: Vec<StreamHeader>,
+    // the ones it moved on from, and until when they are still accepted
+    recv_previous: Vec<(StreamHeader, Instant)>,
+}
+impl KeySchedule {
+    pub fn new(header: StreamHeader, policy: RekeyPolicy, now: Instant) -> Self {
+        let mut keys = Self {
+            policy,
+            send: header.clone(),
+            sent_bytes: 0,
+            sent_packets: 0,
+            send_since: now,
+            recv: header,
+            recv_ahead: Vec::with_capacity(MAX_KEYS_AHEAD),
+            recv_previous: Vec::new(),
+        };
+        keys.fill_ahead();
+        keys
+    }
+    // derives keys until there are MAX_KEYS_AHEAD past the current one
+    fn fill_ahead(&mut self) {
+        while self.recv_ahead.len() < MAX_KEYS_AHEAD {
+            let next = next_key(self.recv_ahead.last().unwrap_or(&self.recv));
+            self.recv_ahead.push(next);
+        }
+    }
+    /// the header to encrypt with
+    pub fn send_header(&self) -> &StreamHeader {
+        &self.send
+    }
+    /// packets holding bytes went out under the current key, what is sent after may be under a new one
+    pub fn on_sent(&mut self, bytes: usize, packets: usize, stats: &StreamStats, now: Instant) {
+        self.sent_bytes += bytes as u64;
+        self.sent_packets += packets as u64;
+        let due = self.policy.bytes.is_some_and(|max| self.sent_bytes >= max)
+            || self
+                .policy
+                .packets
+                .is_some_and(|max| self.sent_packets >= max)
+            || self
+                .policy
+                .interval
+                .is_some_and(|interval| now >= self.send_since + interval);
+        if due {
+            self.send = next_key(&self.send);
+            self.sent_bytes = 0;
+            self.sent_packets = 0;
+            self.send_since = now;
+            stats.add_rekey();
+        }
+    }
+    /// like sym_aes_decrypt, but with whichever key the packet was sent under.
+    /// a packet under one of the next keys means the peer moved on, so this does too
+    pub fn decrypt(
+        &mut self,
+        aad: &[u8],
+        data: &mut [u8],
+        now: Instant,
+    ) -> Result<(Vec<u8>, StreamHeader, Vec<usize>), NetworkError> {
+        let error = match sym_aes_decrypt(&self.recv, aad, data) {
+            Ok(decrypted) => return Ok(decrypted),
+            Err(e) => e,
+        };
+        let ahead = self
+            .recv_ahead
+            .iter()
+            .enumerate()
+            .find_map(|(step, key)| Some((step, sym_aes_decrypt(key, aad, data).ok()?)));
+        if let Some((step, decrypted)) = ahead {
+            // the keys stepped over may still have packets on the way, like the current one
+            let mut passed: Vec<StreamHeader> = self.recv_ahead.drain(0..=step).collect();
+            let current = passed.pop().unwrap();
+            let previous = std::mem::replace(&mut self.recv, current);
+            let until = now + KEY_OVERLAP;
+            self.recv_previous.retain(|(_, until)| now < *until);
+            self.recv_previous.push((previous, until));
+            self.recv_previous
+                .extend(passed.into_iter().map(|key| (key, until)));
+            // a peer rekeying on every packet mustn't make every forgery cost more
+            let excess = self.recv_previous.len().saturating_sub(MAX_KEYS_AHEAD);
+            self.recv_previous.drain(0..excess);
+            self.fill_ahead();
+            return Ok(decrypted);
+        }
+        self.recv_previous
+            .iter()
+            .filter(|(_, until)| now < *until)
+            .find_map(|(previous, _)| sym_aes_decrypt(previous, aad, data).ok())
+            .ok_or(error)
+    }
+}
+
+#[test]
+fn key_schedule_test() {
+    use crate::encryption::sym_aes_encrypt;
+    let now = Instant::now();
+    let stats = StreamStats::default();
+    let header = StreamHeader::new(0);
+    let mut sender = KeySchedule::new(header.clone(), RekeyPolicy::new(None, Some(2), None), now);
+    let mut receiver = KeySchedule::new(header.clone(), RekeyPolicy::never(), now);
+    let old = sym_aes_encrypt(sender.send_header(), &[], b"old");
+    sender.on_sent(3, 1, &stats, now);
+    assert_eq!(sender.send_header(), &header);
+    sender.on_sent(3, 1, &stats, now);
+    assert_ne!(sender.send_header(), &header);
+    assert_eq!(stats.rekeys(), 1);
+    // the receiver follows the sender to the new key, and still takes the old one for a while
+    let new = sym_aes_encrypt(sender.send_header(), &[], b"new");
+    assert_eq!(
+        receiver.decrypt(&[], &mut new.clone(), now).unwrap().0,
+        b"new"
+    );
+    assert_eq!(
+        receiver.decrypt(&[], &mut old.clone(), now).unwrap().0,
+        b"old"
+    );
+    assert!(receiver
+        .decrypt(&[], &mut old.clone(), now + KEY_OVERLAP)
+        .is_err());
+    assert_eq!(
+        receiver.decrypt(&[], &mut new.clone(), now).unwrap().0,
+        b"new"
+    );
+    // the sender can move on twice before anything under the key in between arrives
+    sender.on_sent(6, 2, &stats, now);
+    let between = sym_aes_encrypt(sender.send_header(), &[], b"between");
+    sender.on_sent(6, 2, &stats, now);
+    let skipped = sym_aes_encrypt(sender.send_header(), &[], b"skipped");
+    assert_eq!(
+        receiver.decrypt(&[], &mut skipped.clone(), now).unwrap().0,
+        b"skipped"
+    );
+    assert_eq!(
+        receiver.decrypt(&[], &mut between.clone(), now).unwrap().0,
+        b"between"
+    );
+    assert_eq!(
+        receiver.decrypt(&[], &mut new.clone(), now).unwrap().0,
+        b"new"
+    );
+    // but only so many times
+    for _ in 0..=MAX_KEYS_AHEAD {
+        sender.on_sent(6, 2, &stats, now);
+    }
+    let too_far = sym_aes_encrypt(sender.send_header(), &[], b"too far");
+    assert!(receiver.decrypt(&[], &mut too_far.clone(), now).is_err());
+    // time alone is enough
+    let mut sender = KeySchedule::new(
+        header.clone(),
+        RekeyPolicy::new(None, None, Some(Duration::from_secs(1))),
+        now,
+    );
+    sender.on_sent(1, 1, &stats, now);
+    assert_eq!(sender.send_header(), &header);
+    sender.on_sent(1, 1, &stats, now + Duration::from_secs(1));
+    assert_ne!(sender.send_header(), &header);
+}
@@ -0,0 +1,434 @@
+//! reliable, ordered delivery for streams in DeliveryMode::Reliable, run by the stream's session.
+//! messages are cut into segments that fit in a datagram, and every segment is numbered.
+//! the receiver acknowledges what it has with a SelectiveAck, and any segment not acknowledged
+//! within the retransmission timeout is sent again, so a big message only resends what got lost.
+//! how much may be in flight, and how fast it goes out, is up to the stream's CongestionControl
+use crate::channel::ChannelInfo;
+use crate::congestion::CongestionControl;
+use crate::fragment::MAX_MESSAGE_LEN;
+use crate::protocol::{Message, PacketType, SelectiveAck, StreamHeader};
+use crate::session::Link;
+use crate::{seal_overhead, IncomingMsg, NetworkError};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::time::Duration;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tokio::time::Instant;
+
+/// segments sent but not yet acknowledged, sending waits once this many are outstanding
+pub const MAX_IN_FLIGHT: usize = 256;
+/// segment number u64 | 1 if the segment ends its message u8
+pub const SEGMENT_HEADER_LEN: usize = 9;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(10);
+/// a segment is resent early once this many acknowledgements have skipped over it
+const DUP_ACK_THRESHOLD: u32 = 3;
+/// most ranges of out of order segments one acknowledgement reports
+const MAX_SACK_BLOCKS: usize = 16;
+// how often delivery is retried while the stream isn't reading
+const DELIVERY_RETRY: Duration = Duration::from_millis(20);
+
+/// retransmission timeout from smoothed round trip times, as in rfc 6298
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+        }
+    }
+}
+impl RttEstimator {
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.max(rtt) - srtt.min(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let rto = self.srtt.unwrap_or_default() + self.rttvar * 4;
+        self.rto = rto.max(MIN_RTO).min(MAX_RTO);
+    }
+    /// called when the timer runs out, so a path that got slower isn't flooded
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+    /// None until the first sample
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    data: Vec<u8>,
+    last: bool,
+    sent: Instant,
+    // rtt samples aren't taken from retransmitted segments, it isn't known which copy was acknowledged
+    retransmitted: bool,
+    // acknowledgements that reported later segments but not this one
+    skipped: u32,
+}
+
+#[derive(Debug)]
+struct Segment {
+    // the packet that brought it, and when
+    message: Message,
+    last: bool,
+}
+
+#[derive(Debug)]
+pub struct Reliable {
+    // sending, segments wait in queued until the window has room for them
+    queued: VecDeque<(Vec<u8>, bool)>,
+    next_msg: u64,
+    in_flight: BTreeMap<u64, InFlight>,
+    // bytes of the segments in in_flight
+    bytes_in_flight: usize,
+    rtt: RttEstimator,
+    congestion: Box<dyn CongestionControl>,
+    // receiving, segments from next_deliver on that haven't been taken into a message yet
+    received: BTreeMap<u64, Segment>,
+    next_deliver: u64,
+    // the first segment that hasn't arrived
+    cumulative: u64,
+    // the segments of the message being put together
+    assembling: Vec<u8>,
+    // the message being put together got too long, its segments are dropped up to its last
+    oversized: bool,
+    // whole messages that didn't fit in the stream's channel yet
+    ready: VecDeque<Message>,
+    // None for the stream itself
+    channel: Option<ChannelInfo>,
+}
+
+impl Reliable {
+    pub fn new(congestion: Box<dyn CongestionControl>) -> Self {
+        Self {
+            queued: VecDeque::new(),
+            next_msg: 0,
+            in_flight: BTreeMap::new(),
+            bytes_in_flight: 0,
+            rtt: RttEstimator::default(),
+            congestion,
+            received: BTreeMap::new(),
+            next_deliver: 0,
+            cumulative: 0,
+            assembling: Vec::new(),
+            oversized: false,
+            ready: VecDeque::new(),
+            channel: None,
+        }
+    }
+    /// for a channel of the stream, its packets are sent on the channel
+    pub fn for_channel(congestion: Box<dyn CongestionControl>, channel: ChannelInfo) -> Self {
+        Self {
+            channel: Some(channel),
+            ..Self::new(congestion)
+        }
+    }
+    /// false while the last message still has segments waiting to be sent,
+    /// or once MAX_IN_FLIGHT segments, or the congestion window, wait for acknowledgement
+    pub fn window_open(&self) -> bool {
+        self.queued.is_empty() && self.has_room()
+    }
+    fn has_room(&self) -> bool {
+        self.in_flight.len() < MAX_IN_FLIGHT && self.bytes_in_flight < self.congestion.window()
+    }
+    /// everything has been sent and acknowledged
+    pub fn is_idle(&self) -> bool {
+        self.queued.is_empty() && self.in_flight.is_empty()
+    }
+    /// when on_timeout should be called, if there is anything to wait for
+    pub fn deadline(&self) -> Option<Instant> {
+        let retransmit = self
+            .in_flight
+            .values()
+            .map(|entry| entry.sent + self.rtt.rto())
+            .min();
+        if self.ready.is_empty() && !self.received.contains_key(&self.next_deliver) {
+            return retransmit;
+        }
+        let retry = Instant::now() + DELIVERY_RETRY;
+        Some(retransmit.map_or(retry, |deadline| deadline.min(retry)))
+    }
+    /// cuts the message into segments for the mtu, those that don't fit in the window wait for acknowledgements
+    pub async fn send_new(&mut self, link: &mut Link, data: Vec<u8>) -> Result<(), NetworkError> {
+        let segment_len = segment_len(link.max_payload(self.channel.as_ref()));
+        if data.len() <= segment_len {
+            self.queued.push_back((data, true));
+        } else {
+            let count = data.len().div_ceil(segment_len);
+            for (i, segment) in data.chunks(segment_len).enumerate() {
+                self.queued.push_back((segment.to_vec(), i + 1 == count));
+            }
+        }
+        self.send_queued(link).await
+    }
+    async fn send_queued(&mut self, link: &mut Link) -> Result<(), NetworkError> {
+        while self.has_room() {
+            let (data, last) = match self.queued.pop_front() {
+                Some(segment) => segment,
+                None => break,
+            };
+            let msg = self.next_msg;
+            self.next_msg += 1;
+            self.bytes_in_flight += data.len();
+            self.congestion
+                .on_sent(data.len(), Instant::now().into_std());
+            self.in_flight.insert(
+                msg,
+                InFlight {
+                    data,
+                    last,
+                    sent: Instant::now(),
+                    retransmitted: false,
+                    skipped: 0,
+                },
+            );
+            self.transmit(link, msg).await?;
+        }
+        Ok(())
+    }
+    // every copy gets a new sequence number, so the replay window lets retransmissions through
+    async fn transmit(&mut self, link: &mut Link, msg: u64) -> Result<(), NetworkError> {
+        let entry = match self.in_flight.get_mut(&msg) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        entry.sent = Instant::now();
+        let mut payload = Vec::with_capacity(SEGMENT_HEADER_LEN + entry.data.len());
+        payload.extend_from_slice(&msg.to_be_bytes());
+        payload.push(entry.last as u8);
+        payload.extend_from_slice(&entry.data);
+        link.send_on(self.channel.as_ref(), PacketType::RawData, &payload)
+            .await
+    }
+    async fn retransmit(&mut self, link: &mut Link, msg: u64) -> Result<(), NetworkError> {
+        if let Some(entry) = self.in_flight.get_mut(&msg) {
+            entry.retransmitted = true;
+            entry.skipped = 0;
+            link.stats().add_retransmit();
+        }
+        self.transmit(link, msg).await
+    }
+    pub async fn on_timeout(&mut self, link: &mut Link) -> Result<(), NetworkError> {
+        let now = Instant::now();
+        let rto = self.rtt.rto();
+        let expired: Vec<u64> = self
+            .in_flight
+            .iter()
+            .filter(|(_, entry)| entry.sent + rto <= now)
+            .map(|(msg, _)| *msg)
+            .collect();
+        if !expired.is_empty() {
+            self.rtt.backoff();
+            self.congestion.on_timeout(now.into_std());
+            link.set_pacing_rate(self.congestion.pacing_rate());
+        }
+        for msg in expired {
+            self.retransmit(link, msg).await?;
+        }
+        Ok(())
+    }
+    /// seq is that of the packet that brought the segment
+    pub async fn on_data(
+        &mut self,
+        link: &mut Link,
+        seq: u64,
+        mut payload: Vec<u8>,
+    ) -> Result<(), NetworkError> {
+        if payload.len() < SEGMENT_HEADER_LEN {
+            return Ok(());
+        }
+        let msg = u64::from_be_bytes(payload[0..8].try_into().unwrap());
+        let last = payload[8] != 0;
+        // anything further ahead would let the peer grow the buffer without limit
+        let limit = self.next_deliver + MAX_IN_FLIGHT as u64;
+        if msg >= self.cumulative && msg < limit && !self.received.contains_key(&msg) {
+            payload.drain(0..SEGMENT_HEADER_LEN);
+            let arrived = Instant::now().into_std();
+            let message = Message::new(payload, PacketType::RawData, seq, arrived);
+            self.received.insert(msg, Segment { message, last });
+            while self.received.contains_key(&self.cumulative) {
+                self.cumulative += 1;
+            }
+        }
+        // duplicates are acknowledged too, the previous acknowledgement may have been lost
+        let mut blocks: Vec<(u64, u64)> = Vec::new();
+        for msg in self.received.range(self.cumulative..).map(|(msg, _)| *msg) {
+            match blocks.last_mut() {
+                Some((_, end)) if *end == msg => *end += 1,
+                _ => {
+                    if blocks.len() == MAX_SACK_BLOCKS {
+                        break;
+                    }
+                    blocks.push((msg, msg + 1))
+                }
+            }
+        }
+        let ack = SelectiveAck::new(self.cumulative, blocks).to_raw();
+        link.send_on(self.channel.as_ref(), PacketType::RawDataAck, &ack)
+            .await
+    }
+    pub async fn on_ack(&mut self, link: &mut Link, payload: &[u8]) -> Result<(), NetworkError> {
+        let ack = match SelectiveAck::from_raw(payload) {
+            Ok(ack) => ack,
+            Err(_) => return Ok(()),
+        };
+        let now = Instant::now();
+        let acked: Vec<u64> = self
+            .in_flight
+            .keys()
+            .filter(|msg| ack.acks(**msg))
+            .copied()
+            .collect();
+        let mut sample = None;
+        let mut acked_bytes = 0;
+        for msg in acked {
+            if let Some(entry) = self.in_flight.remove(&msg) {
+                self.bytes_in_flight -= entry.data.len();
+                acked_bytes += entry.data.len();
+                if !entry.retransmitted {
+                    sample = Some(now - entry.sent);
+                }
+            }
+        }
+        if let Some(rtt) = sample {
+            self.rtt.sample(rtt);
+            link.stats().set_rtt(self.rtt.srtt().unwrap_or(rtt));
+        }
+        if acked_bytes > 0 {
+            self.congestion.on_ack(acked_bytes, sample, now.into_std());
+        }
+        // segments the receiver skipped over are most likely lost, so they are resent without waiting
+        let mut lost = Vec::new();
+        if let Some((_, highest)) = ack.blocks().last() {
+            for (msg, entry) in self.in_flight.range_mut(..*highest) {
+                entry.skipped += 1;
+                if entry.skipped == DUP_ACK_THRESHOLD {
+                    lost.push(*msg);
+                }
+            }
+        }
+        if !lost.is_empty() {
+            self.congestion.on_loss(now.into_std());
+        }
+        link.set_pacing_rate(self.congestion.pacing_rate());
+        for msg in lost {
+            self.retransmit(link, msg).await?;
+        }
+        self.send_queued(link).await
+    }
+    // takes the segments next in order into assembling, until a message is complete
+    fn assemble(&mut self) -> Option<Message> {
+        while let Some(Segment { message, last }) = self.received.remove(&self.next_deliver) {
+            self.next_deliver += 1;
+            if last && self.assembling.is_empty() && !self.oversized {
+                return Some(message);
+            }
+            // longer than any message the peer may send, so none of it is handed over
+            self.oversized |= self.assembling.len() + message.data().len() > MAX_MESSAGE_LEN;
+            if self.oversized {
+                self.assembling = Vec::new();
+            } else {
+                self.assembling.extend_from_slice(message.data());
+            }
+            if !last || std::mem::take(&mut self.oversized) {
+                continue;
+            }
+            // the message is numbered and timed by its last segment
+            let data = std::mem::take(&mut self.assembling);
+            let (seq, arrived) = (message.seq(), message.arrived());
+            return Some(Message::new(data, PacketType::RawData, seq, arrived));
+        }
+        None
+    }
+    /// the messages next in order, for when the stream is closing and they can't wait for deliver
+    pub fn take_ready(&mut self) -> Vec<Message> {
+        let mut ready: Vec<Message> = self.ready.drain(..).collect();
+        while let Some(message) = self.assemble() {
+            ready.push(message);
+        }
+        ready
+    }
+    /// hands over messages in order, those that don't fit in the channel wait for the next try.
+    /// returns false once nothing reads from the stream anymore
+    pub fn deliver(&mut self, to_app: &mut Sender<IncomingMsg>) -> bool {
+        loop {
+            let message = match self.ready.pop_front().or_else(|| self.assemble()) {
+                Some(message) => message,
+                None => return true,
+            };
+            match to_app.try_send(Ok(message)) {
+                Ok(()) => (),
+                Err(TrySendError::Full(msg)) => {
+                    if let Ok(message) = msg {
+                        self.ready.push_front(message);
+                    }
+                    return true;
+                }
+                // the message is acknowledged but dropped
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+    }
+}
+
+fn segment_len(max_payload: usize) -> usize {
+    max_payload.saturating_sub(SEGMENT_HEADER_LEN).max(1)
+}
+/// number of segments send_new cuts a message len bytes long into, wrapped is what else each
+/// segment carries, the channel header for a channel
+pub fn segment_count(header: &StreamHeader, len: usize, mtu: usize, wrapped: usize) -> usize {
+    let max_payload = mtu.saturating_sub(seal_overhead(header) + wrapped);
+    len.div_ceil(segment_len(max_payload)).max(1)
+}
+/// acknowledges a segment sent on a channel that was closed, so the peer stops resending it
+pub async fn discard(
+    link: &mut Link,
+    channel: &ChannelInfo,
+    payload: &[u8],
+) -> Result<(), NetworkError> {
+    if payload.len() < SEGMENT_HEADER_LEN {
+        return Ok(());
+    }
+    let msg = u64::from_be_bytes(payload[0..8].try_into().unwrap());
+    let ack = SelectiveAck::new(0, vec![(msg, msg + 1)]).to_raw();
+    link.send_on(Some(channel), PacketType::RawDataAck, &ack)
+        .await
+}
+
+#[test]
+fn rtt_estimator_test() {
+    let mut rtt = RttEstimator::default();
+    assert_eq!(rtt.rto(), INITIAL_RTO);
+    rtt.sample(Duration::from_millis(100));
+    assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+    // srtt + 4 * rttvar = 100 + 4 * 50
+    assert_eq!(rtt.rto(), Duration::from_millis(300));
+    for _ in 0..50 {
+        rtt.sample(Duration::from_millis(10));
+    }
+    // a fast, steady path still never goes below the minimum
+    assert_eq!(rtt.rto(), MIN_RTO);
+    for _ in 0..10 {
+        rtt.backoff();
+    }
+    assert_eq!(rtt.rto(), MAX_RTO);
+}
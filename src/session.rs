@@ -0,0 +1,615 @@
+//! every stream has a session, a task between the stream and the socket. it encrypts what the stream sends,
+//! decrypts and checks what arrives, puts fragments back together, and answers Admin packets,
+//! so the stream, and its split halves, only ever see whole plain messages.
+//! once every sending handle of the stream is gone, the session closes the stream with the peer,
+//! and it keeps an eye on whether the peer is still there, giving up on it once it goes quiet for too long.
+//! datagrams reach it by connection id, so when the newest one comes from another address the peer moved,
+//! and the session follows it there, once the peer answered a challenge sent to the new address
+use crate::channel::{
+    add_inputs, next_input, ChannelHandle, ChannelInfo, ChannelInput, Channels, OpenedChannel,
+    CHANNEL_HEADER_LEN, STREAM_PRIORITY,
+};
+use crate::fragment::{seal_fragments, Reassembler};
+use crate::liveness::{Liveness, LivenessState};
+use crate::pmtu::{Pmtu, BASE_MTU};
+use crate::protocol::{
+    AdminMsg, ConnectionId, DeliveryMode, Message, PacketType, ReplayWindow, SeqCounter,
+    StreamHeader, StreamStats, CID_LEN,
+};
+use crate::rekey::KeySchedule;
+use crate::reliable::Reliable;
+use crate::{
+    seal, seal_overhead, AsyncQuery, Datagram, IncomingMsg, NetworkError, OutgoingMsg, Query,
+    StreamContext, Streams, STREAM_CHANNEL_LEN,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::time::{delay_until, timeout_at, Instant};
+
+// the timer when there is nothing to wait for
+const IDLE: Duration = Duration::from_secs(3600);
+// how long a Close waits to be acknowledged before it is sent again
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+// the peer is given up on after this many Close packets go unanswered
+const MAX_CLOSE_ATTEMPTS: u32 = 3;
+// a path challenge that goes unanswered for this long is sent again with the next packet from the address
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(1);
+// pacing holds a datagram back no more than 10ms, the session can't read from the socket while it waits
+const MIN_PACING_RATE: f64 = (BASE_MTU * 100) as f64;
+
+/// the way out to the peer, everything sent on it is encrypted
+#[derive(Debug)]
+pub struct Link {
+    keys: KeySchedule,
+    remote_addr: SocketAddr,
+    // the connection id the peer receives on
+    remote_id: ConnectionId,
+    seq: SeqCounter,
+    stats: Arc<StreamStats>,
+    to_net: Sender<OutgoingMsg>,
+    // bytes per second data goes out at, None sends it right away
+    pacing_rate: Option<f64>,
+    next_send: Instant,
+}
+impl Link {
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+    /// spaces out the RawData packets sent from now on, acknowledgements and Admin packets are never held back
+    pub fn set_pacing_rate(&mut self, rate: Option<f64>) {
+        self.pacing_rate = rate.map(|rate| rate.max(MIN_PACING_RATE));
+    }
+    /// largest payload sent on channel that fits in a single datagram
+    pub fn max_payload(&self, channel: Option<&ChannelInfo>) -> usize {
+        let wrapped = channel.map_or(0, |_| CHANNEL_HEADER_LEN);
+        let overhead = seal_overhead(self.keys.send_header()) + wrapped;
+        self.stats.mtu().saturating_sub(overhead)
+    }
+    /// split into fragments if it doesn't fit the mtu
+    pub async fn send(
+        &mut self,
+        packet_type: PacketType,
+        payload: &[u8],
+    ) -> Result<(), NetworkError> {
+        self.send_paced(packet_type, payload, packet_type == PacketType::RawData)
+            .await
+    }
+    /// the packets of a channel other than the stream itself are wrapped in a Channel packet
+    pub async fn send_on(
+        &mut self,
+        channel: Option<&ChannelInfo>,
+        packet_type: PacketType,
+        payload: &[u8],
+    ) -> Result<(), NetworkError> {
+        let info = match channel {
+            Some(info) => info,
+            None => return self.send(packet_type, payload).await,
+        };
+        let mut wrapped = info.to_raw(packet_type);
+        wrapped.extend_from_slice(payload);
+        self.send_paced(
+            PacketType::Channel,
+            &wrapped,
+            packet_type == PacketType::RawData,
+        )
+        .await
+    }
+    async fn send_paced(
+        &mut self,
+        packet_type: PacketType,
+        payload: &[u8],
+        paced: bool,
+    ) -> Result<(), NetworkError> {
+        let mtu = self.stats.mtu();
+        let header = self.keys.send_header();
+        let packets = seal_fragments(header, &self.seq, self.remote_id, packet_type, payload, mtu);
+        let (bytes, count) = (packets.iter().map(Vec::len).sum(), packets.len());
+        for packet in packets {
+            if paced {
+                self.pace(packet.len()).await;
+            }
+            self.to_net.send((packet, self.remote_addr)).await?;
+        }
+        self.keys
+            .on_sent(bytes, count, &self.stats, Instant::now().into_std());
+        Ok(())
+    }
+    async fn pace(&mut self, len: usize) {
+        let rate = match self.pacing_rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        let now = Instant::now();
+        if self.next_send > now {
+            delay_until(self.next_send).await;
+        }
+        self.next_send = self.next_send.max(now) + Duration::from_secs_f64(len as f64 / rate);
+    }
+    /// a single packet to addr instead of the peer, such as a path challenge
+    pub async fn send_to(
+        &mut self,
+        addr: SocketAddr,
+        packet_type: PacketType,
+        payload: &[u8],
+    ) -> Result<(), NetworkError> {
+        let header = self.keys.send_header();
+        let packet = seal(header, &self.seq, self.remote_id, packet_type, payload);
+        let len = packet.len();
+        self.to_net.send((packet, addr)).await?;
+        self.keys
+            .on_sent(len, 1, &self.stats, Instant::now().into_std());
+        Ok(())
+    }
+    /// padded with zeros so the datagram is exactly size bytes
+    pub async fn send_padded(
+        &mut self,
+        packet_type: PacketType,
+        payload: &[u8],
+        size: usize,
+    ) -> Result<(), NetworkError> {
+        let header = self.keys.send_header();
+        let mut payload = payload.to_vec();
+        payload.resize(size.saturating_sub(seal_overhead(header)), 0);
+        let packet = seal(header, &self.seq, self.remote_id, packet_type, &payload);
+        self.to_net.send((packet, self.remote_addr)).await?;
+        self.keys
+            .on_sent(size, 1, &self.stats, Instant::now().into_std());
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    link: Link,
+    mode: DeliveryMode,
+    to_app: Sender<IncomingMsg>,
+    app_gone: bool,
+    window: ReplayWindow,
+    fragments: Reassembler,
+    // sequence number of the last message delivered, for latest only streams
+    latest: u64,
+    reliable: Reliable,
+    channels: Channels,
+    pmtu: Pmtu,
+    liveness: Liveness,
+    streams: Streams,
+    // the connection id the stream is routed by
+    local_id: ConnectionId,
+    // the address the peer seems to have moved to, the challenge sent there, and when
+    challenge: Option<(SocketAddr, u64, Instant)>,
+    // when the last Close was sent, and how many have been
+    closing: Option<(Instant, u32)>,
+    // the Close was acknowledged, or given up on
+    closed: bool,
+    peer_closed: bool,
+}
+
+/// start the session of a stream, net are the channels to the socket,
+/// the returned channels carry plain messages, and are used by the stream in their place,
+/// along with the handle it opens and accepts channels with.
+/// initiator is true for the end that started the handshake
+#[allow(clippy::too_many_arguments)]
+pub fn spawn(
+    header: StreamHeader,
+    remote_addr: SocketAddr,
+    local_id: ConnectionId,
+    remote_id: ConnectionId,
+    initiator: bool,
+    mode: DeliveryMode,
+    context: &StreamContext,
+    stats: Arc<StreamStats>,
+    net: AsyncQuery<OutgoingMsg, Datagram>,
+) -> (AsyncQuery<OutgoingMsg, IncomingMsg>, ChannelHandle) {
+    let (to_net, from_net) = net.into_split();
+    let congestion = context.config().congestion_control();
+    let (channels, from_stream, handle) = Channels::new(initiator, congestion);
+    let (app_sender, from_app) = channel(STREAM_CHANNEL_LEN);
+    let (to_app, app_receiver) = channel(STREAM_CHANNEL_LEN);
+    let mut session = Session {
+        link: Link {
+            keys: KeySchedule::new(header, context.config().rekey(), Instant::now().into_std()),
+            remote_addr,
+            remote_id,
+            seq: SeqCounter::default(),
+            stats,
+            to_net,
+            pacing_rate: None,
+            next_send: Instant::now(),
+        },
+        mode,
+        to_app,
+        app_gone: false,
+        window: ReplayWindow::default(),
+        fragments: Reassembler::default(),
+        latest: 0,
+        reliable: Reliable::new(congestion.build()),
+        channels,
+        pmtu: Pmtu::new(Instant::now().into_std()),
+        liveness: Liveness::new(
+            context.config().keepalive(),
+            context.config().idle_timeout(),
+            Instant::now().into_std(),
+        ),
+        streams: context.streams().clone(),
+        local_id,
+        challenge: None,
+        closing: None,
+        closed: false,
+        peer_closed: false,
+    };
+    if mode != DeliveryMode::Reliable {
+        // nothing acknowledges what the other modes send, so they go out at their send limit
+        // rather than in bursts as fast as the app hands them over
+        let stream_limit = context.config().send_limit().bytes_per_sec();
+        let socket_limit = context.send_limit().lock().limit().bytes_per_sec();
+        let rate = stream_limit.into_iter().chain(socket_limit).min();
+        session.link.set_pacing_rate(rate.map(|rate| rate as f64));
+    }
+    tokio::spawn(session.run(from_app, from_net, from_stream));
+    (AsyncQuery::create(app_sender, app_receiver), handle)
+}
+
+impl Session {
+    // runs until the socket goes away, the stream is closed by either end, or the peer is given up on
+    async fn run(
+        mut self,
+        from_app: Receiver<OutgoingMsg>,
+        mut from_net: Receiver<Datagram>,
+        mut from_stream: Receiver<OpenedChannel>,
+    ) {
+        let mut stream_open = true;
+        // the stream, as channel 0, and the channels that can still send, from the highest priority down
+        let stream = ChannelInfo::new(0, self.mode, STREAM_PRIORITY);
+        let mut inputs = vec![ChannelInput::new(stream, from_app)];
+        loop {
+            let mut blocked = self.channels.blocked();
+            if self.mode == DeliveryMode::Reliable && !self.reliable.window_open() {
+                blocked.push(0);
+            }
+            let result = tokio::select! {
+                opened = from_stream.recv(), if stream_open => match opened {
+                    Some(opened) => {
+                        self.channels.add(opened);
+                        Ok(())
+                    }
+                    None => {
+                        stream_open = false;
+                        Ok(())
+                    }
+                },
+                (id, msg) = next_input(&mut inputs, &blocked), if !inputs.is_empty() => match msg {
+                    Some(data) if id == 0 => self.on_app(data).await,
+                    Some(data) => self.channels.on_app(&mut self.link, id, data).await,
+                    None => {
+                        inputs.retain(|input| input.info().id() != id);
+                        self.channels.close(id);
+                        Ok(())
+                    }
+                },
+                packet = from_net.recv() => match packet {
+                    Some(Ok((mut data, from))) => self.on_packet(&mut data, from).await,
+                    Some(Err(e)) => {
+                        self.on_error(e);
+                        Ok(())
+                    }
+                    None => return,
+                },
+                _ = delay_until(self.deadline()) => self.on_timer().await,
+            };
+            // the socket is gone
+            if result.is_err() {
+                return;
+            }
+            add_inputs(&mut inputs, self.channels.take_inputs());
+            if self.mode == DeliveryMode::Reliable && !self.reliable.deliver(&mut self.to_app) {
+                self.app_gone = true;
+            }
+            self.channels.deliver();
+            self.channels.drop_closed();
+            // the peer is told once the stream and all its channels are done,
+            // and everything they sent has been acknowledged
+            let idle = self.reliable.is_idle() && self.channels.is_idle();
+            let close = inputs.is_empty() && self.closing.is_none() && idle;
+            if close && self.send_close().await.is_err() {
+                return;
+            }
+            // the stream and its halves check this once the session is gone
+            self.link.stats.set_liveness(self.liveness.state());
+            if self.closed || self.peer_closed || self.liveness.state() == LivenessState::Dead {
+                break;
+            }
+        }
+        // nothing more is sent
+        drop(from_stream);
+        drop(inputs);
+        let Session {
+            mut link,
+            mut to_app,
+            mut reliable,
+            mut channels,
+            streams,
+            local_id,
+            peer_closed,
+            liveness,
+            ..
+        } = self;
+        if peer_closed || liveness.state() == LivenessState::Dead {
+            // what arrived before the Close is still handed over, however long the stream takes to read it
+            for message in reliable.take_ready() {
+                if to_app.send(Ok(message)).await.is_err() {
+                    break;
+                }
+            }
+            channels.finish().await;
+        }
+        // the stream and its channels read the end of the messages from here on
+        drop(to_app);
+        drop(channels);
+        if peer_closed {
+            linger(&mut link, &mut from_net).await;
+        }
+        drop(from_net);
+        streams.lock().await.remove(&local_id);
+    }
+    fn deadline(&self) -> Instant {
+        let pmtu = Instant::from_std(self.pmtu.deadline());
+        let deadline = match self.reliable.deadline() {
+            Some(reliable) => reliable.min(pmtu),
+            None => pmtu,
+        };
+        let deadline = match self.channels.deadline() {
+            Some(channels) => deadline.min(channels),
+            None => deadline,
+        };
+        let deadline = match self.closing {
+            Some((sent, _)) => deadline.min(sent + CLOSE_TIMEOUT),
+            None => deadline,
+        };
+        let deadline = match self.liveness.deadline() {
+            Some(liveness) => deadline.min(Instant::from_std(liveness)),
+            None => deadline,
+        };
+        let deadline = match self.fragments.deadline() {
+            Some(fragments) => deadline.min(Instant::from_std(fragments)),
+            None => deadline,
+        };
+        deadline.min(Instant::now() + IDLE)
+    }
+    // sends the Close again each time it goes unanswered, until the peer is given up on
+    async fn send_close(&mut self) -> Result<(), NetworkError> {
+        let attempts = self.closing.map_or(0, |(_, attempts)| attempts);
+        if attempts == MAX_CLOSE_ATTEMPTS {
+            let _ = self
+                .to_app
+                .try_send(Err(NetworkError::IOError(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "the peer never acknowledged the close",
+                ))));
+            self.closed = true;
+            return Ok(());
+        }
+        self.closing = Some((Instant::now(), attempts + 1));
+        self.link
+            .send(PacketType::Admin, &AdminMsg::Close.to_raw())
+            .await
+    }
+    async fn on_timer(&mut self) -> Result<(), NetworkError> {
+        self.reliable.on_timeout(&mut self.link).await?;
+        self.channels.on_timeout(&mut self.link).await?;
+        if let Some((sent, _)) = self.closing {
+            if sent + CLOSE_TIMEOUT <= Instant::now() {
+                self.send_close().await?;
+            }
+        }
+        self.fragments
+            .expire(Instant::now().into_std(), &self.link.stats);
+        if self.liveness.poll(Instant::now().into_std()) {
+            self.link
+                .send(PacketType::Admin, &AdminMsg::Ping.to_raw())
+                .await?;
+        }
+        if let Some((id, size)) = self.pmtu.poll(Instant::now().into_std()) {
+            let probe = AdminMsg::Probe {
+                id,
+                size: size as u32,
+            };
+            self.link
+                .send_padded(PacketType::Admin, &probe.to_raw(), size)
+                .await?;
+        }
+        Ok(())
+    }
+    async fn on_app(&mut self, data: Vec<u8>) -> Result<(), NetworkError> {
+        match self.mode {
+            DeliveryMode::Reliable => self.reliable.send_new(&mut self.link, data).await,
+            DeliveryMode::Unordered | DeliveryMode::LatestOnly => {
+                self.link.send(PacketType::RawData, &data).await
+            }
+        }
+    }
+    fn on_error(&mut self, error: NetworkError) {
+        // a probe too big for the interface, that is an answer rather than an error
+        if too_big(&error) {
+            self.pmtu.on_too_big();
+        } else {
+            let _ = self.to_app.try_send(Err(error));
+        }
+    }
+    async fn on_packet(&mut self, data: &mut [u8], from: SocketAddr) -> Result<(), NetworkError> {
+        // the socket only routes datagrams with a connection id
+        if data.len() < CID_LEN {
+            return Ok(());
+        }
+        let now = Instant::now().into_std();
+        // the connection id isn't encrypted, but it is authenticated along with the rest
+        let (cid, sealed) = data.split_at_mut(CID_LEN);
+        let (payload, remote_header, _) = match self.link.keys.decrypt(cid, sealed, now) {
+            Ok(decrypted) => decrypted,
+            // forged or tampered with, anyone who knows the connection id can send these,
+            // so they are only counted, telling the stream would let them flood it
+            Err(_) => {
+                self.link.stats.add_forgery();
+                return Ok(());
+            }
+        };
+        // the sequence number is authenticated, so a replayed packet can't be disguised as a new one
+        let seq = remote_header.seq();
+        let newest = seq > self.window.highest();
+        if !self.window.accept(seq) {
+            self.link.stats.add_replay();
+            return Ok(());
+        }
+        self.liveness.on_heard(Instant::now().into_std());
+        // older packets may still come the old way, so only the newest one moves the stream.
+        // anyone on the path can change where a packet seems to come from, so the new address has to answer first
+        if newest && from != self.link.remote_addr {
+            self.challenge(from).await?;
+        }
+        let (packet_type, payload) = match remote_header.packet_type() {
+            PacketType::Fragment => match self.fragments.add(&payload, &self.link.stats) {
+                Some(message) => message,
+                None => return Ok(()),
+            },
+            packet_type => (packet_type, payload),
+        };
+        match packet_type {
+            PacketType::RawData => self.on_data(seq, payload).await,
+            PacketType::RawDataAck => self.reliable.on_ack(&mut self.link, &payload).await,
+            PacketType::Admin | PacketType::AdminAck => self.on_admin(&payload, from).await,
+            PacketType::Channel => self.channels.on_packet(&mut self.link, seq, &payload).await,
+            PacketType::Fragment => Ok(()),
+        }
+    }
+    // everything still goes to the old address until the new one echoes the token.
+    // one sent again keeps its token, so a late answer to the first still counts
+    async fn challenge(&mut self, addr: SocketAddr) -> Result<(), NetworkError> {
+        let now = Instant::now();
+        let token = match self.challenge {
+            Some((pending, _, sent)) if pending == addr && now < sent + CHALLENGE_TIMEOUT => {
+                return Ok(())
+            }
+            Some((pending, token, _)) if pending == addr => token,
+            _ => OsRng.next_u64(),
+        };
+        self.challenge = Some((addr, token, now));
+        let challenge = AdminMsg::PathChallenge { token };
+        self.link
+            .send_to(addr, PacketType::Admin, &challenge.to_raw())
+            .await
+    }
+    // the peer is at a new address, such as after its nat rebound, the mtu of the new path is found again
+    async fn migrate(&mut self, addr: SocketAddr) {
+        self.link.remote_addr = addr;
+        if let Some((peer, _)) = self.streams.lock().await.get_mut(&self.local_id) {
+            *peer = addr;
+        }
+        self.pmtu = Pmtu::new(Instant::now().into_std());
+        self.link.stats.set_mtu(BASE_MTU);
+        self.link.stats.add_migration();
+    }
+    // seq is that of the packet that completed the message
+    async fn on_data(&mut self, seq: u64, payload: Vec<u8>) -> Result<(), NetworkError> {
+        match self.mode {
+            DeliveryMode::Reliable => {
+                return self.reliable.on_data(&mut self.link, seq, payload).await
+            }
+            DeliveryMode::LatestOnly if seq < self.latest => {
+                self.link.stats.add_stale();
+                return Ok(());
+            }
+            DeliveryMode::LatestOnly => self.latest = seq,
+            DeliveryMode::Unordered => (),
+        }
+        let message = Message::new(payload, PacketType::RawData, seq, Instant::now().into_std());
+        // like the socket, a stream that isn't keeping up loses messages rather than holding up the rest
+        if let Err(TrySendError::Closed(_)) = self.to_app.try_send(Ok(message)) {
+            self.app_gone = true;
+        }
+        Ok(())
+    }
+    async fn on_admin(&mut self, payload: &[u8], from: SocketAddr) -> Result<(), NetworkError> {
+        match AdminMsg::from_raw(payload) {
+            Ok(AdminMsg::Probe { id, size }) => {
+                let ack = AdminMsg::ProbeAck { id, size };
+                self.link.send(PacketType::AdminAck, &ack.to_raw()).await
+            }
+            Ok(AdminMsg::ProbeAck { id, .. }) => {
+                if self.pmtu.on_ack(id, Instant::now().into_std()) {
+                    self.link.stats.set_mtu(self.pmtu.mtu());
+                }
+                Ok(())
+            }
+            Ok(AdminMsg::Close) => {
+                self.peer_closed = true;
+                self.link
+                    .send(PacketType::AdminAck, &AdminMsg::CloseAck.to_raw())
+                    .await
+            }
+            Ok(AdminMsg::CloseAck) => {
+                self.closed = self.closing.is_some();
+                Ok(())
+            }
+            Ok(AdminMsg::Ping) => {
+                self.link
+                    .send(PacketType::AdminAck, &AdminMsg::Pong.to_raw())
+                    .await
+            }
+            // that it got through is all that matters, and on_packet has seen to that
+            Ok(AdminMsg::Pong) => Ok(()),
+            // the answer goes back the way the challenge came, that is the path being checked
+            Ok(AdminMsg::PathChallenge { token }) => {
+                let response = AdminMsg::PathResponse { token };
+                self.link
+                    .send_to(from, PacketType::AdminAck, &response.to_raw())
+                    .await
+            }
+            Ok(AdminMsg::PathResponse { token }) => {
+                if let Some((addr, expected, _)) = self.challenge {
+                    if addr == from && token == expected {
+                        self.challenge = None;
+                        self.migrate(addr).await;
+                    }
+                }
+                Ok(())
+            }
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+// answers the peer for a while after it closed, in case the CloseAck was lost and the Close comes again
+async fn linger(link: &mut Link, from_net: &mut Receiver<Datagram>) {
+    let until = Instant::now() + CLOSE_TIMEOUT * MAX_CLOSE_ATTEMPTS;
+    while let Ok(Some(packet)) = timeout_at(until, from_net.recv()).await {
+        let mut data = match packet {
+            Ok((data, _)) if data.len() >= CID_LEN => data,
+            _ => continue,
+        };
+        let now = Instant::now().into_std();
+        let (cid, sealed) = data.split_at_mut(CID_LEN);
+        if let Ok((payload, remote_header, _)) = link.keys.decrypt(cid, sealed, now) {
+            let close = matches!(AdminMsg::from_raw(&payload), Ok(AdminMsg::Close));
+            if remote_header.packet_type() == PacketType::Admin && close {
+                let _ = link
+                    .send(PacketType::AdminAck, &AdminMsg::CloseAck.to_raw())
+                    .await;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn too_big(error: &NetworkError) -> bool {
+    match error {
+        NetworkError::IOError(e) => e.raw_os_error() == Some(libc::EMSGSIZE),
+        _ => false,
+    }
+}
+#[cfg(not(target_os = "linux"))]
+fn too_big(_error: &NetworkError) -> bool {
+    false
+}
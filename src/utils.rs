@@ -160,6 +160,12 @@ pub enum NetworkError {
     NotSync,
     #[error(display = "No Data Yet")]
     Empty,
+    #[error(display = "Packet Authentication Failed")]
+    AuthenticationFailed,
+    #[error(display = "Message Too Large: {} bytes", _0)]
+    MessageTooLarge(#[error(no_from)] usize),
+    #[error(display = "Rate Limited: {}", _0)]
+    RateLimited(#[error(no_from)] String),
 }
 impl<T> From<AsyncSendError<T>> for NetworkError {
     fn from(error: AsyncSendError<T>) -> NetworkError {
@@ -0,0 +1,112 @@
+mod common;
+
+use bytes::Bytes;
+use common::*;
+use futures::{SinkExt, StreamExt};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use verifyudp::{
+    AsyncNetworkHost, AsyncSend, ConnectionRequest, DeliveryMode, PacketType, SllpDatagrams,
+    SllpIo, SllpSocket,
+};
+
+#[tokio::test]
+async fn messages_are_received_one_at_a_time() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let large = vec![7u8; 5000];
+    stream.send(b"first").await.unwrap();
+    stream.send(b"second").await.unwrap();
+    stream.send(&large).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    let mut messages = Vec::new();
+    for _ in 0..3 {
+        messages.push(accepted.recv_msg().await.unwrap().unwrap());
+    }
+    // sequence numbers go up in the order the messages were sent
+    messages.sort_by_key(|message| message.seq());
+    assert_eq!(messages[0].data(), b"first");
+    assert_eq!(messages[1].data(), b"second");
+    assert_eq!(messages[2].data(), &large[..]);
+    for message in &messages {
+        assert_eq!(message.packet_type(), PacketType::RawData);
+        assert!(message.arrived() <= Instant::now());
+    }
+    stream.close().await.unwrap();
+    assert!(accepted.recv_msg().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn tokio_io_over_a_reliable_stream() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let stream = client
+        .connect_with(&server_peer, DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let (send, recv) = stream.into_split();
+    let mut writer = SllpIo::new(send).unwrap();
+    let accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    let mut reader = SllpIo::new(accepted).unwrap();
+    // more than one write is sent as, so it goes in several messages, and reads split them up again
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut received = vec![0; data.len()];
+    let mut sending = &data[..];
+    let (copied, read) = tokio::join!(
+        tokio::io::copy(&mut sending, &mut writer),
+        reader.read_exact(&mut received)
+    );
+    assert_eq!(copied.unwrap(), data.len() as u64);
+    read.unwrap();
+    assert_eq!(received, data);
+    // the whole stream works the same as its halves
+    reader.write_all(b"done").await.unwrap();
+    let mut reply = [0; 4];
+    SllpIo::new(recv)
+        .unwrap()
+        .read_exact(&mut reply)
+        .await
+        .unwrap();
+    assert_eq!(&reply, b"done");
+    // shutting down closes the stream, the peer reads its end
+    writer.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).await.unwrap(), 0);
+    assert!(writer.write_all(b"too late").await.is_err());
+}
+
+#[tokio::test]
+async fn datagram_sink_and_stream() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let stream = client.connect(&server_peer).await.unwrap();
+    // bytes could go missing between reads
+    let unordered = client.connect(&server_peer).await.unwrap();
+    assert!(SllpIo::new(unordered).is_err());
+    let mut sink = SllpDatagrams::new(stream);
+    sink.send(Bytes::from_static(b"one")).await.unwrap();
+    sink.send(Bytes::from_static(b"two")).await.unwrap();
+    let accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    // dropping the sending half would close the stream
+    let (_send, recv) = accepted.into_split();
+    let mut incoming = SllpDatagrams::new(recv);
+    let mut received = vec![
+        incoming.next().await.unwrap().unwrap(),
+        incoming.next().await.unwrap().unwrap(),
+    ];
+    received.sort();
+    assert_eq!(received, [&b"one"[..], &b"two"[..]]);
+    // the stream ends once the peer closes it
+    sink.into_inner().close().await.unwrap();
+    assert!(incoming.next().await.is_none());
+}
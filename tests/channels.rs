@@ -0,0 +1,61 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, ConnectionRequest, DeliveryMode, SllpSocket,
+};
+
+/// channels open inside a stream without another handshake, each with its own delivery mode
+#[tokio::test]
+async fn channels_share_one_stream() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let mut reliable = stream
+        .open_channel(DeliveryMode::Reliable, 1)
+        .await
+        .unwrap();
+    let mut latest = stream
+        .open_channel(DeliveryMode::LatestOnly, 0)
+        .await
+        .unwrap();
+    for i in 0..10u8 {
+        reliable.send(&[i]).await.unwrap();
+    }
+    latest.send(b"latest").await.unwrap();
+    stream.send(b"stream").await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    let mut inbuf = Vec::new();
+    accepted.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"stream");
+    let mut channels = vec![
+        accepted.accept_channel().await.unwrap(),
+        accepted.accept_channel().await.unwrap(),
+    ];
+    channels.sort_by_key(|channel| channel.info().priority());
+    assert_eq!(channels[0].info(), latest.info());
+    assert_eq!(channels[1].info(), reliable.info());
+    let mut inbuf = Vec::new();
+    channels[0].recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"latest");
+    for i in 0..10u8 {
+        let mut inbuf = Vec::new();
+        channels[1].recv(&mut inbuf).await.unwrap();
+        assert_eq!(inbuf, [i]);
+    }
+    // the peer answers on the same channel
+    channels[1].send(b"back").await.unwrap();
+    let mut inbuf = Vec::new();
+    reliable.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"back");
+    // the stream closes once its channels are gone
+    drop(latest);
+    drop(reliable);
+    stream.close().await.unwrap();
+    let mut inbuf = Vec::new();
+    assert!(channels[1].recv(&mut inbuf).await.unwrap().is_empty());
+}
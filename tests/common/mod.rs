@@ -0,0 +1,47 @@
+//! what the integration tests share. generating an rsa key takes seconds, so rather than each test
+//! making its own, they all take theirs from the few kept in keys.json
+#![allow(dead_code)]
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use verifyudp::{ArtificeConfig, ArtificeHostData, HandshakeMode, PeerList, PubKeyComp};
+
+/// which of the keys a socket gets, peers that have to tell each other apart get different ones
+#[derive(Debug, Clone, Copy)]
+pub enum Key {
+    Server,
+    Client,
+    Stranger,
+}
+
+fn host(key: Key) -> ArtificeHostData {
+    static KEYS: OnceLock<Vec<ArtificeHostData>> = OnceLock::new();
+    let keys = KEYS.get_or_init(|| serde_json::from_str(include_str!("keys.json")).unwrap());
+    keys[key as usize].clone()
+}
+
+/// a socket on addr, taking its handshakes on the same ip
+pub fn config_on(addr: SocketAddr, key: Key) -> ArtificeConfig {
+    let mut config = ArtificeConfig::new(addr.into(), host(key), false);
+    config.set_handshake_addr(addr);
+    config
+}
+// port 0 everywhere, so the os hands out free ports and tests can run in parallel
+pub fn config(key: Key) -> ArtificeConfig {
+    config_on(SocketAddr::from(([127, 0, 0, 1], 0)), key)
+}
+/// a socket that runs its handshakes over udp
+pub fn udp_config(key: Key) -> ArtificeConfig {
+    let mut config = config(key);
+    config.set_handshake_mode(HandshakeMode::Udp);
+    config
+}
+pub fn pubkey(config: &ArtificeConfig) -> PubKeyComp {
+    PubKeyComp::from(config.host_data().privkeycomp())
+}
+
+pub struct TrustList(pub Vec<PubKeyComp>);
+impl PeerList for TrustList {
+    fn verify_peer(&self, peer: &PubKeyComp) -> bool {
+        self.0.contains(peer)
+    }
+}
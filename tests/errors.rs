@@ -0,0 +1,123 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, ConnectionRequest, NetworkError, RateLimit, RemotePeer,
+    SllpSocket, StreamConfig,
+};
+
+/// misbehaving peers produce errors, but the socket keeps serving everyone else
+#[tokio::test]
+async fn errors_dont_stop_the_socket() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+
+    // garbage instead of a client hello is reported on incoming
+    let mut garbage = TcpStream::connect(server_peer.handshake_addr()).await.unwrap();
+    garbage.write_all(&[0, 0, 0, 4, 1, 2, 3, 4]).await.unwrap();
+    assert!(server.next().await.unwrap().is_err());
+
+    // a peer that never finishes its handshake doesn't hold up the next one
+    let _stalled = TcpStream::connect(server_peer.handshake_addr()).await.unwrap();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+
+    // datagrams from unknown addresses are ignored
+    let mut spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    spoofer.send_to(b"not a real packet", server_peer.socket_addr()).await.unwrap();
+    stream.send(b"still alive").await.unwrap();
+    let mut inbuf = Vec::new();
+    accepted.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"still alive");
+}
+
+/// a server that takes the handshake connection and never answers fails connect with a timeout
+#[tokio::test]
+async fn silent_server_times_out() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            held.push(socket);
+        }
+    });
+    let peer =
+        RemotePeer::with_handshake_port(addr.into(), addr.port(), pubkey(&config(Key::Server)));
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    match timeout(Duration::from_secs(15), client.connect(&peer)).await {
+        Ok(Err(NetworkError::IOError(e))) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+        Ok(other) => panic!("connected to a silent server: {:?}", other.map(|_| ())),
+        Err(_) => panic!("connect never gave up"),
+    }
+}
+
+/// sending faster than a limit allows fails, and a peer sending too fast has its datagrams dropped
+#[tokio::test]
+async fn rate_limits() {
+    let mut server_config = config(Key::Server);
+    server_config.set_recv_limit(RateLimit::new(None, Some(20)));
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let server_peer = server.remote_peer();
+
+    let mut client_config = config(Key::Client);
+    let mut stream_config = StreamConfig::default();
+    stream_config.set_send_limit(RateLimit::new(Some(1000), None));
+    client_config.set_stream_config(stream_config);
+    client_config.set_send_limit(RateLimit::new(None, Some(10)));
+    let client = SllpSocket::client_only(&client_config).await.unwrap();
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let _accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    // the stream runs out of bytes, the halves of a split stream share what is left
+    stream.send(&[0; 600]).await.unwrap();
+    let (mut send, _recv) = stream.into_split();
+    send.send(&[0; 400]).await.unwrap();
+    match send.send(&[0; 100]).await {
+        Err(NetworkError::RateLimited(_)) => (),
+        other => panic!("sent over the stream limit: {:?}", other),
+    }
+    // every stream of the socket counts against the packets of the socket
+    let mut other_server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let mut other = client.connect(&other_server.remote_peer()).await.unwrap();
+    let _other_accepted = unsafe { other_server.next().await.unwrap().unwrap().unverify() };
+    let mut sent = 0;
+    let error = loop {
+        match other.send(b"x").await {
+            Ok(_) => sent += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(sent <= 10);
+    assert!(format!("{}", error).contains("socket"));
+    assert!(send.send(b"y").await.is_err());
+
+    // the server drops what is over its limit, and says so once
+    let noisy = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut noisy_stream = noisy.connect(&server_peer).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    for _ in 0..100 {
+        noisy_stream.send(b"noise").await.unwrap();
+    }
+    let (mut received, mut limited) = (0, 0);
+    loop {
+        let mut inbuf = Vec::new();
+        match timeout(Duration::from_millis(500), accepted.recv(&mut inbuf)).await {
+            Ok(Ok(_)) => received += 1,
+            Ok(Err(NetworkError::RateLimited(_))) => limited += 1,
+            Ok(Err(e)) => panic!("{}", e),
+            Err(_) => break,
+        }
+    }
+    assert!(received < 50);
+    assert_eq!(limited, 1);
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use std::time::Duration;
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, ConnectionRequest, DeliveryMode, NetworkError,
+    SllpSocket, BASE_MTU, MAX_MESSAGE_LEN, MAX_MTU,
+};
+
+#[tokio::test]
+async fn large_messages_are_fragmented() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    // bigger than a udp datagram can carry, so it can only arrive in fragments
+    let message: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    for mode in &[DeliveryMode::Unordered, DeliveryMode::Reliable] {
+        let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+        let mut stream = client.connect_with(&server_peer, *mode).await.unwrap();
+        let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+        stream.send(&message).await.unwrap();
+        let mut inbuf = Vec::new();
+        accepted.recv(&mut inbuf).await.unwrap();
+        assert_eq!(inbuf, message);
+    }
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    match stream.send(&vec![0; MAX_MESSAGE_LEN + 1]).await {
+        Err(NetworkError::MessageTooLarge(_)) => (),
+        other => panic!("sent an oversized message: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn mtu_is_discovered() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let stream = client.connect(&server_peer).await.unwrap();
+    let accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    assert!(stream.mtu() >= BASE_MTU);
+    // loopback takes anything, so both ends probe their way up to the largest size they try
+    for _ in 0..100 {
+        if stream.mtu() > MAX_MTU - 16 && accepted.mtu() > MAX_MTU - 16 {
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+    panic!("mtu stuck at {} and {}", stream.mtu(), accepted.mtu());
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::time::timeout;
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, ConnectionRequest, HandshakeMode, NetworkError,
+    RemotePeer, SllpSocket,
+};
+
+#[tokio::test]
+async fn mutual_authentication() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+
+    // an honest client, trusted by the server, can exchange data in both directions
+    let client_config = config(Key::Client);
+    let client = SllpSocket::client_only(&client_config).await.unwrap();
+    let trusted = TrustList(vec![pubkey(&client_config)]);
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let mut accepted = server
+        .next()
+        .await
+        .unwrap()
+        .unwrap()
+        .verify(&trusted)
+        .unwrap();
+    stream.send(b"hello server").await.unwrap();
+    let mut inbuf = Vec::new();
+    accepted.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"hello server");
+    accepted.send(b"hello client").await.unwrap();
+    let mut inbuf = Vec::new();
+    stream.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"hello client");
+
+    // the host at the expected address doesn't hold the expected key, so it is an impostor
+    let expected = RemotePeer::with_handshake_port(
+        server.local_addr().into(),
+        server.handshake_addr().unwrap().port(),
+        pubkey(&config(Key::Stranger)),
+    );
+    match client.connect(&expected).await {
+        Err(NetworkError::ConnectionDenied(_)) => (),
+        other => panic!("connected to an impostor: {:?}", other),
+    }
+    // the server sees the client abandon the handshake
+    assert!(server.next().await.unwrap().is_err());
+
+    // a client with a valid key that isn't trusted completes the handshake, but fails verification
+    let stranger_config = config(Key::Stranger);
+    let stranger = SllpSocket::client_only(&stranger_config).await.unwrap();
+    stranger.connect(&server_peer).await.unwrap();
+    let request = server.next().await.unwrap().unwrap();
+    assert!(request.verify(&trusted).is_err());
+}
+
+/// several sockets can listen on one machine, over ipv6 as well as ipv4
+#[tokio::test]
+async fn many_sockets_one_host() {
+    let mut servers = Vec::new();
+    for _ in 0..3 {
+        servers.push(
+            SllpSocket::from_host_config(&config(Key::Server))
+                .await
+                .unwrap(),
+        );
+    }
+    let addr = SocketAddr::from((Ipv6Addr::LOCALHOST, 0));
+    // skip ipv6 if the sandbox has no loopback for it
+    if let Ok(server) = SllpSocket::from_host_config(&config_on(addr, Key::Server)).await {
+        servers.push(server);
+    }
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let v6_client = SllpSocket::client_only(&config_on(addr, Key::Client)).await;
+    for server in servers.iter_mut() {
+        let peer = server.remote_peer();
+        assert_ne!(peer.socket_addr().port(), 0);
+        assert_ne!(peer.handshake_addr().port(), 0);
+        let mut stream = if peer.socket_addr().is_ipv6() {
+            v6_client.as_ref().unwrap().connect(&peer).await.unwrap()
+        } else {
+            client.connect(&peer).await.unwrap()
+        };
+        let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+        stream
+            .send(&peer.handshake_addr().port().to_be_bytes())
+            .await
+            .unwrap();
+        let mut inbuf = Vec::new();
+        accepted.recv(&mut inbuf).await.unwrap();
+        assert_eq!(inbuf, peer.handshake_addr().port().to_be_bytes());
+    }
+}
+
+/// the handshake can run over the udp socket alone, with the same guarantees as over tcp
+#[tokio::test]
+async fn udp_only_handshake() {
+    let mut server = SllpSocket::from_host_config(&udp_config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    assert_eq!(server_peer.handshake_addr(), server_peer.socket_addr());
+
+    let client_config = udp_config(Key::Client);
+    let client = SllpSocket::client_only(&client_config).await.unwrap();
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let trusted = TrustList(vec![pubkey(&client_config)]);
+    let mut accepted = server
+        .next()
+        .await
+        .unwrap()
+        .unwrap()
+        .verify(&trusted)
+        .unwrap();
+    stream.send(b"no tcp needed").await.unwrap();
+    let mut inbuf = Vec::new();
+    accepted.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"no tcp needed");
+    accepted.send(b"indeed").await.unwrap();
+    let mut inbuf = Vec::new();
+    stream.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"indeed");
+
+    // an impostor is still caught
+    let expected = RemotePeer::new(server.local_addr().into(), pubkey(&client_config));
+    match client.connect(&expected).await {
+        Err(NetworkError::ConnectionDenied(_)) => (),
+        other => panic!("connected to an impostor: {:?}", other),
+    }
+}
+
+/// a socket can open several streams to the same peer, at the same time, each with its own key and state
+#[tokio::test]
+async fn several_streams_to_one_peer() {
+    for mode in &[HandshakeMode::Tcp, HandshakeMode::Udp] {
+        let config = |key| {
+            let mut config = config(key);
+            config.set_handshake_mode(*mode);
+            config
+        };
+        let mut server = SllpSocket::from_host_config(&config(Key::Server))
+            .await
+            .unwrap();
+        let server_peer = server.remote_peer();
+        let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+        let (first, second) =
+            tokio::join!(client.connect(&server_peer), client.connect(&server_peer));
+        let mut streams = vec![first.unwrap(), second.unwrap()];
+        for (i, stream) in streams.iter_mut().enumerate() {
+            stream.send(&[i as u8]).await.unwrap();
+        }
+        // every accepted stream gets the message of one of them, and answers it
+        let mut accepted = Vec::new();
+        for _ in 0..2 {
+            let mut stream = unsafe { server.next().await.unwrap().unwrap().unverify() };
+            let mut inbuf = Vec::new();
+            stream.recv(&mut inbuf).await.unwrap();
+            stream.send(&inbuf).await.unwrap();
+            accepted.push((inbuf, stream));
+        }
+        accepted.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(accepted[0].0, [0]);
+        assert_eq!(accepted[1].0, [1]);
+        for (i, stream) in streams.iter_mut().enumerate() {
+            let mut inbuf = Vec::new();
+            stream.recv(&mut inbuf).await.unwrap();
+            assert_eq!(inbuf, [i as u8]);
+        }
+        // closing one leaves the other be
+        streams.pop().unwrap().close().await.unwrap();
+        streams[0].send(b"still open").await.unwrap();
+        let mut inbuf = Vec::new();
+        accepted[0].1.recv(&mut inbuf).await.unwrap();
+        assert_eq!(inbuf, b"still open");
+    }
+}
+
+/// what a client sends as soon as connect returns isn't lost, the server routes the stream
+/// before it confirms the handshake
+#[tokio::test]
+async fn sending_right_after_connect() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    for i in 0..20u8 {
+        let mut stream = client.connect(&server_peer).await.unwrap();
+        stream.send(&[i]).await.unwrap();
+        let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+        let mut inbuf = Vec::new();
+        timeout(Duration::from_secs(5), accepted.recv(&mut inbuf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inbuf, [i]);
+    }
+}
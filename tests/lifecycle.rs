@@ -0,0 +1,106 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use std::time::Duration;
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, ConnectionRequest, DeliveryMode, LivenessState,
+    RekeyPolicy, SllpSocket, StreamConfig,
+};
+
+#[tokio::test]
+async fn close_ends_the_stream() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut stream = client
+        .connect_with(&server_peer, DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    for i in 0..50u32 {
+        stream.send(&i.to_be_bytes()).await.unwrap();
+    }
+    // everything sent before the close arrives, then the end of the stream
+    stream.close().await.unwrap();
+    for i in 0..50u32 {
+        let mut inbuf = Vec::new();
+        assert_eq!(accepted.recv(&mut inbuf).await.unwrap().len(), 1);
+        assert_eq!(inbuf, i.to_be_bytes());
+    }
+    for _ in 0..2 {
+        let mut inbuf = Vec::new();
+        assert!(accepted.recv(&mut inbuf).await.unwrap().is_empty());
+    }
+    assert!(accepted.send(b"anyone there").await.is_err());
+
+    // dropping a stream closes it as well
+    let client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let stream = client.connect(&server_peer).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    drop(stream);
+    let mut inbuf = Vec::new();
+    let lens = tokio::time::timeout(Duration::from_secs(5), accepted.recv(&mut inbuf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(lens.is_empty());
+}
+
+/// a stream nothing is sent on stays open past the idle timeout, as long as the peer answers its pings
+#[tokio::test]
+async fn keepalives_hold_quiet_streams_open() {
+    let mut stream_config = StreamConfig::default();
+    stream_config.set_keepalive(Some(Duration::from_millis(100)));
+    stream_config.set_idle_timeout(Some(Duration::from_millis(500)));
+    let mut server_config = config(Key::Server);
+    server_config.set_stream_config(stream_config.clone());
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let mut client_config = config(Key::Client);
+    client_config.set_stream_config(stream_config);
+    let client = SllpSocket::client_only(&client_config).await.unwrap();
+    let mut stream = client.connect(&server.remote_peer()).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    // it may be waiting on the answer to a ping right now, but it hasn't given up on the peer
+    assert_ne!(stream.stats().liveness(), LivenessState::Dead);
+    stream.send(b"still here").await.unwrap();
+    let mut inbuf = Vec::new();
+    accepted.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"still here");
+}
+
+/// both ends move on to new keys every few packets, and the split halves of the streams keep up
+#[tokio::test]
+async fn split_streams_rekey() {
+    let mut stream_config = StreamConfig::default();
+    stream_config.set_rekey(RekeyPolicy::new(None, Some(5), None));
+    let mut server_config = config(Key::Server);
+    server_config.set_stream_config(stream_config.clone());
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let mut client_config = config(Key::Client);
+    client_config.set_stream_config(stream_config);
+    let client = SllpSocket::client_only(&client_config).await.unwrap();
+    let stream = client
+        .connect_with(&server.remote_peer(), DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    let (mut client_send, mut client_recv) = stream.into_split();
+    let (mut server_send, mut server_recv) = accepted.into_split();
+    for i in 0..40u32 {
+        client_send.send(&i.to_be_bytes()).await.unwrap();
+        server_send.send(&i.to_be_bytes()).await.unwrap();
+    }
+    for i in 0..40u32 {
+        for recv in &mut [&mut client_recv, &mut server_recv] {
+            let mut inbuf = Vec::new();
+            recv.recv(&mut inbuf).await.unwrap();
+            assert_eq!(inbuf, i.to_be_bytes());
+        }
+    }
+    assert!(client_recv.stats().rekeys() >= 8);
+    assert!(server_recv.stats().rekeys() >= 8);
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_for, timeout};
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, ConnectionRequest, RemotePeer, SllpSocket,
+};
+
+// a nat in front of a client, it forwards what the client sends to server from an outside socket.
+// rebinding moves it to a new outside socket, as a nat does once its mapping expires
+async fn nat(server: SocketAddr) -> (SocketAddr, mpsc::Sender<oneshot::Sender<()>>) {
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut inside = UdpSocket::bind(local).await.unwrap();
+    let inside_addr = inside.local_addr().unwrap();
+    let (rebind, mut rebinds) = mpsc::channel::<oneshot::Sender<()>>(1);
+    tokio::spawn(async move {
+        let mut outside = UdpSocket::bind(local).await.unwrap();
+        let mut client = None;
+        let mut inbuf = vec![0u8; 65535];
+        let mut outbuf = vec![0u8; 65535];
+        loop {
+            tokio::select! {
+                received = inside.recv_from(&mut inbuf) => if let Ok((len, from)) = received {
+                    client = Some(from);
+                    let _ = outside.send_to(&inbuf[..len], &server).await;
+                },
+                received = outside.recv_from(&mut outbuf) => match (received, client) {
+                    (Ok((len, _)), Some(client)) => {
+                        let _ = inside.send_to(&outbuf[..len], &client).await;
+                    }
+                    _ => (),
+                },
+                rebound = rebinds.recv() => match rebound {
+                    Some(done) => {
+                        outside = UdpSocket::bind(local).await.unwrap();
+                        let _ = done.send(());
+                    }
+                    None => break,
+                },
+            }
+        }
+    });
+    (inside_addr, rebind)
+}
+
+/// a client behind a nat keeps its stream when the nat maps it to a new port, the server follows it there
+/// once the client answered its challenge at the new port
+#[tokio::test]
+async fn streams_follow_the_peer_to_a_new_address() {
+    let server_config = udp_config(Key::Server);
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let (nat_addr, mut rebind) = nat(server.local_addr()).await;
+    let client = SllpSocket::client_only(&udp_config(Key::Client))
+        .await
+        .unwrap();
+    let server_peer = RemotePeer::new(nat_addr.into(), pubkey(&server_config));
+    let mut stream = client.connect(&server_peer).await.unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    for (moves, message) in [b"before", b"after!"].iter().enumerate() {
+        stream.send(*message).await.unwrap();
+        let mut inbuf = Vec::new();
+        accepted.recv(&mut inbuf).await.unwrap();
+        assert_eq!(&inbuf, message);
+        let moved = async {
+            while accepted.stats().migrations() < moves as u64 {
+                delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), moved).await.unwrap();
+        // the answer only gets back if the server sends it to where the client is now
+        accepted.send(*message).await.unwrap();
+        let mut inbuf = Vec::new();
+        stream.recv(&mut inbuf).await.unwrap();
+        assert_eq!(&inbuf, message);
+        let (done, rebound) = oneshot::channel();
+        rebind.send(done).await.unwrap();
+        rebound.await.unwrap();
+    }
+    assert_eq!(accepted.stats().migrations(), 1);
+    assert_eq!(stream.stats().migrations(), 0);
+}
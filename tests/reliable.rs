@@ -0,0 +1,158 @@
+mod common;
+
+use common::*;
+use futures::StreamExt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use verifyudp::{
+    AsyncNetworkHost, AsyncRecv, AsyncSend, CongestionAlgorithm, ConnectionRequest, DeliveryMode,
+    RemotePeer, SllpSocket, StreamConfig,
+};
+
+#[tokio::test]
+async fn reliable_stream_in_order() {
+    let mut server = SllpSocket::from_host_config(&config(Key::Server))
+        .await
+        .unwrap();
+    let server_peer = server.remote_peer();
+    let client_config = config(Key::Client);
+    let client = SllpSocket::client_only(&client_config).await.unwrap();
+    let mut stream = client
+        .connect_with(&server_peer, DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let trusted = TrustList(vec![pubkey(&client_config)]);
+    let mut accepted = server
+        .next()
+        .await
+        .unwrap()
+        .unwrap()
+        .verify(&trusted)
+        .unwrap();
+    // the server picks up the mode the client asked for
+    assert_eq!(accepted.delivery_mode(), DeliveryMode::Reliable);
+    // more than fit in flight at once, so sending has to wait for acknowledgements
+    let sending = async {
+        for i in 0..1000u32 {
+            stream.send(&i.to_be_bytes()).await.unwrap();
+        }
+    };
+    let receiving = async {
+        for i in 0..1000u32 {
+            let mut inbuf = Vec::new();
+            accepted.recv(&mut inbuf).await.unwrap();
+            assert_eq!(inbuf, i.to_be_bytes());
+        }
+    };
+    futures::join!(sending, receiving);
+    accepted.send(b"all there").await.unwrap();
+    let mut inbuf = Vec::new();
+    stream.recv(&mut inbuf).await.unwrap();
+    assert_eq!(inbuf, b"all there");
+}
+
+/// a bulk transfer to one peer doesn't hold up the other peers of the socket
+#[tokio::test]
+async fn bulk_stream_shares_the_socket() {
+    let mut server_config = config(Key::Server);
+    let mut stream_config = StreamConfig::default();
+    stream_config.set_congestion_control(CongestionAlgorithm::Bbr);
+    server_config.set_stream_config(stream_config);
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let server_peer = server.remote_peer();
+    let bulk_client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut bulk = bulk_client
+        .connect_with(&server_peer, DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let mut bulk_accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    let other_client = SllpSocket::client_only(&config(Key::Client)).await.unwrap();
+    let mut other = other_client
+        .connect_with(&server_peer, DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let mut other_accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+
+    let chunk = vec![7u8; 60_000];
+    let sending = async {
+        for _ in 0..100 {
+            bulk_accepted.send(&chunk).await.unwrap();
+        }
+    };
+    let receiving = async {
+        for _ in 0..100 {
+            let mut inbuf = Vec::new();
+            bulk.recv(&mut inbuf).await.unwrap();
+            assert_eq!(inbuf, chunk);
+        }
+    };
+    let other_traffic = async {
+        for i in 0..20u32 {
+            let started = Instant::now();
+            other_accepted.send(&i.to_be_bytes()).await.unwrap();
+            let mut inbuf = Vec::new();
+            other.recv(&mut inbuf).await.unwrap();
+            assert_eq!(inbuf, i.to_be_bytes());
+            assert!(started.elapsed() < Duration::from_secs(1));
+        }
+    };
+    futures::join!(sending, receiving, other_traffic);
+    assert!(bulk_accepted.stats().rtt().is_some());
+}
+
+// stands between a client and server, and loses one datagram in ten in each direction
+async fn lossy_link(server: SocketAddr) -> SocketAddr {
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut inside = UdpSocket::bind(local).await.unwrap();
+    let inside_addr = inside.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut outside = UdpSocket::bind(local).await.unwrap();
+        let mut client = None;
+        let mut inbuf = vec![0u8; 65535];
+        let mut outbuf = vec![0u8; 65535];
+        let lost = || rand::random::<u8>() < 26;
+        loop {
+            tokio::select! {
+                received = inside.recv_from(&mut inbuf) => if let Ok((len, from)) = received {
+                    client = Some(from);
+                    if !lost() {
+                        let _ = outside.send_to(&inbuf[..len], &server).await;
+                    }
+                },
+                received = outside.recv_from(&mut outbuf) => if let (Ok((len, _)), Some(client)) = (received, client) {
+                    if !lost() {
+                        let _ = inside.send_to(&outbuf[..len], &client).await;
+                    }
+                },
+            }
+        }
+    });
+    inside_addr
+}
+
+/// a message of thousands of datagrams gets through a lossy path, only what was lost is sent again
+#[tokio::test]
+async fn large_reliable_messages_survive_loss() {
+    let server_config = udp_config(Key::Server);
+    let mut server = SllpSocket::from_host_config(&server_config).await.unwrap();
+    let link_addr = lossy_link(server.local_addr()).await;
+    let client = SllpSocket::client_only(&udp_config(Key::Client))
+        .await
+        .unwrap();
+    let server_peer = RemotePeer::new(link_addr.into(), pubkey(&server_config));
+    let mut stream = client
+        .connect_with(&server_peer, DeliveryMode::Reliable)
+        .await
+        .unwrap();
+    let mut accepted = unsafe { server.next().await.unwrap().unwrap().unverify() };
+    let message: Vec<u8> = (0..2 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    stream.send(&message).await.unwrap();
+    let mut inbuf = Vec::new();
+    tokio::time::timeout(Duration::from_secs(60), accepted.recv(&mut inbuf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(inbuf == message);
+    assert!(stream.stats().retransmits() > 0);
+}